@@ -0,0 +1,63 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// The conversion module has no dependencies on the rest of the binary, so the bench includes the
+// module; its unit tests only run as part of the binary's
+#[path = "../src/convert.rs"]
+#[cfg_attr(test, allow(dead_code))]
+mod convert;
+
+// Per-pixel conversion into a fresh Vec, as the capture loop used to do it
+fn baseline(frame: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let stride = frame.len() / height;
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let i = stride * y + 4 * x;
+            rgba.extend_from_slice(&[frame[i + 2], frame[i + 1], frame[i], 255]);
+        }
+    }
+    rgba
+}
+
+fn bench_conversion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bgra_to_rgba");
+    for (name, width, height) in [("1080p", 1920, 1080), ("4K", 3840, 2160)] {
+        // Captured frames often pad their rows
+        let stride = width * 4 + 64;
+        let src: Vec<u8> = (0..stride * height).map(|i| (i % 251) as u8).collect();
+        let mut dst = vec![0u8; width * height * 4];
+        group.throughput(Throughput::Bytes((width * height * 4) as u64));
+
+        group.bench_function(BenchmarkId::new("rows", name), |b| {
+            b.iter(|| convert::bgra_to_rgba_rows(black_box(&src), stride, &mut dst, width * 4, width, height))
+        });
+        group.bench_function(BenchmarkId::new("baseline", name), |b| {
+            b.iter(|| baseline(black_box(&src), width, height))
+        });
+    }
+    group.finish();
+}
+
+// Conversions between RGBA and every frame format, at 1080p
+fn bench_formats(c: &mut Criterion) {
+    let (width, height) = (1920u32, 1080u32);
+    let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i % 251) as u8).collect();
+    let mut group = c.benchmark_group("formats");
+    group.throughput(Throughput::Elements((width * height) as u64));
+    for format in convert::PixelFormat::ALL {
+        let name = format!("{:?}", format);
+        let converted = convert::from_rgba(&rgba, width, height, format).unwrap();
+        let stride = format.packed_stride(width);
+
+        group.bench_function(BenchmarkId::new("from_rgba", &name), |b| {
+            b.iter(|| convert::from_rgba(black_box(&rgba), width, height, format))
+        });
+        group.bench_function(BenchmarkId::new("to_rgba", &name), |b| {
+            b.iter(|| convert::to_rgba(black_box(&converted), format, stride, width, height))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_conversion, bench_formats);
+criterion_main!(benches);
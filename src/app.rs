@@ -9,17 +9,22 @@ pub struct UStreamApp {
     receiver : Receiver
 }
 
-impl Default for UStreamApp {
-    fn default() -> Self {
+impl UStreamApp {
+    // Restore persisted caster settings from the eframe storage, if any
+    pub fn new(cc: &eframe::CreationContext) -> Self {
         Self {
             mode: "receiver".to_string(),
-            caster: Caster::new(),
+            caster: Caster::new(cc.storage),
             receiver : Receiver::new(),
         }
     }
 }
 
 impl eframe::App for UStreamApp {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        self.caster.save(storage);
+    }
+
     fn update(&mut self, ctx: &egui::Context, _: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| {
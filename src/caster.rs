@@ -1,97 +1,444 @@
 use eframe::egui;
-use crate::screen::{ScreenCapture, Frame, CropValues, crop, blank, available_displays};
-use crate:: server::StreamServer;
+use crate::screen::{FrameSource, ScreenCapture, CaptureState, Frame, CursorOptions, available_displays};
+use crate::filters::{FilterStage, PreviewDrag, BLANK_FILTER, default_chain};
+use crate::pipeline::{Pipeline, Command, DEFAULT_FPS};
+use crate::pattern::TestPattern;
+use crate::playback::FilePlayback;
+use crate::convert::PixelFormat;
+use crate::compression::{Codec, CompressionSettings, ZSTD_LEVELS};
+use std::path::Path;
+use tokio::sync::watch;
+#[cfg(target_os = "linux")]
+use crate::xshm::{CaptureTarget, X11Capture};
+#[cfg(target_os = "linux")]
+use crate::x11::{WindowInfo, list_windows};
+#[cfg(target_os = "linux")]
+use crate::virtual_display::VirtualDisplay;
 pub struct Caster {
     displays: Vec<String>,
-    capture: Option<ScreenCapture>, // Screen capture instance
-    server: StreamServer,
+    selected_displays: Vec<bool>, // Subset stitched together by "Capture Selected"
+    #[cfg(target_os = "linux")]
+    native_x11: bool, // Capture single monitors with the XShm backend
+    #[cfg(target_os = "linux")]
+    windows: Vec<WindowInfo>, // Application windows offered for capture
+    #[cfg(target_os = "linux")]
+    virtual_display: Option<VirtualDisplay>, // Private X server of the app being streamed
+    #[cfg(target_os = "linux")]
+    app_command: String,
+    #[cfg(target_os = "linux")]
+    app_size: [u32; 2],
+    #[cfg(target_os = "linux")]
+    route_input: bool, // Forward pointer and keyboard on the preview to the app
+    has_source: bool,
+    choosing_source: bool, // Source list shown again while the current one keeps running
+    capture_state: Option<watch::Receiver<CaptureState>>, // Set while a monitor is the source
+    pipeline: Pipeline, // Background capture -> filters -> broadcast task
     current_frame: Option<Frame>, // Current frame data to display
-    crop: CropValues,
+    filters: Vec<FilterStage>, // UI copy of the chain run by the pipeline
+    target_fps: u32,
+    cursor: CursorOptions,
+    compression: CompressionSettings,
+    pattern_size: [u32; 2], // Resolution and rate of the test pattern source
+    pattern_fps: u32,
+    media_path: String, // Image directory or .y4m file to replay
+    media_loop: bool,
+    error_message: Option<String>,
+    drag_stage: Option<usize>, // Filter receiving the current drag on the preview
     is_streaming : bool,
-    is_blank : bool,
 }
 
 impl Caster {
-    // Initialize the Caster with a new ScreenCapture instance
-    pub fn new() -> Self {
-        let capture = None;
-        let server = StreamServer::new();
-        let crop = CropValues::new(0.0, 0.0, 0.0, 0.0);
+    // Initialize the Caster and start its background pipeline
+    pub fn new(storage: Option<&dyn eframe::Storage>) -> Self {
+        let pipeline = Pipeline::new();
+        let mut filters = default_chain();
+        let mut compression = CompressionSettings::new();
+        if let Some(storage) = storage {
+            for stage in filters.iter_mut() {
+                stage.filter.load(storage);
+            }
+            if let Some(settings) = eframe::get_value(storage, "compression") {
+                compression = settings;
+            }
+        }
+        pipeline.send(Command::Filters(filters.clone()));
+        pipeline.send(Command::Compression(compression));
         let displays = available_displays();
         Self {
+            selected_displays: vec![false; displays.len()],
+            #[cfg(target_os = "linux")]
+            native_x11: true,
+            #[cfg(target_os = "linux")]
+            windows: Vec::new(),
+            #[cfg(target_os = "linux")]
+            virtual_display: None,
+            #[cfg(target_os = "linux")]
+            app_command: String::new(),
+            #[cfg(target_os = "linux")]
+            app_size: [1280, 720],
+            #[cfg(target_os = "linux")]
+            route_input: true,
             displays,
-            capture,
-            server,
+            has_source: false,
+            choosing_source: false,
+            capture_state: None,
+            pipeline,
             current_frame: None,
-            crop,
+            filters,
+            target_fps: DEFAULT_FPS,
+            cursor: CursorOptions::new(),
+            compression,
+            pattern_size: [1280, 720],
+            pattern_fps: 30,
+            media_path: String::new(),
+            media_loop: true,
+            error_message: None,
+            drag_stage: None,
             is_streaming: false,
-            is_blank: false
+        }
+    }
+
+    // Feed the pipeline from any frame source (monitor, test pattern, file...)
+    pub fn set_source(&mut self, source: Box<dyn FrameSource>) {
+        // A launched app only lives as long as it is the source
+        #[cfg(target_os = "linux")]
+        {
+            self.virtual_display = None;
+        }
+        self.capture_state = source.state();
+        self.pipeline.send(Command::Source(source));
+        self.has_source = true;
+        self.choosing_source = false;
+    }
+
+    // Use a screen capture as the source, or report why it could not start
+    fn start_capture<S: FrameSource + 'static>(&mut self, capture: Result<S, String>) {
+        match capture {
+            Ok(capture) => {
+                self.error_message = None;
+                self.set_source(Box::new(capture));
+            }
+            Err(err) => self.error_message = Some(format!("Error: {}", err)),
+        }
+    }
+
+    pub fn save(&self, storage: &mut dyn eframe::Storage) {
+        for stage in &self.filters {
+            stage.filter.save(storage);
+        }
+        eframe::set_value(storage, "compression", &self.compression);
+    }
+
+    fn is_blank(&self) -> bool {
+        self.filters.iter().any(|stage| stage.enabled && stage.filter.name() == BLANK_FILTER)
+    }
+
+    // The blank button toggles the blank stage of the filter chain
+    fn set_blank(&mut self, is_blank: bool) {
+        for stage in self.filters.iter_mut().filter(|stage| stage.filter.name() == BLANK_FILTER) {
+            stage.enabled = is_blank;
+        }
+        self.pipeline.send(Command::Filters(self.filters.clone()));
+    }
+
+    // Send pointer and keyboard events on the preview to the app in the virtual display.
+    // Positions are mapped back through the filters, the source being the whole display.
+    #[cfg(target_os = "linux")]
+    fn forward_preview_input(&mut self, ui: &egui::Ui, response: &egui::Response) {
+        let Some(display) = &mut self.virtual_display else {
+            return;
+        };
+        let rect = response.rect;
+        let hovered = response.hovered();
+        for event in ui.input(|i| i.events.clone()) {
+            let result = match event {
+                egui::Event::PointerMoved(pos) if rect.contains(pos) => {
+                    let fraction = (pos - rect.min) / rect.size();
+                    let source = stage_position(&self.filters, 0, fraction.to_pos2());
+                    display.pointer_move(source.x, source.y)
+                }
+                egui::Event::PointerButton { pos, button, pressed, .. } if rect.contains(pos) || !pressed => {
+                    let button = match button {
+                        egui::PointerButton::Primary => 1,
+                        egui::PointerButton::Middle => 2,
+                        egui::PointerButton::Secondary => 3,
+                        _ => continue,
+                    };
+                    display.pointer_button(button, pressed)
+                }
+                egui::Event::MouseWheel { delta, .. } if hovered => {
+                    // One wheel click per event: buttons 4/5 scroll up/down, 6/7 left/right
+                    let vertical = if delta.y > 0.0 { Some(4) } else if delta.y < 0.0 { Some(5) } else { None };
+                    let horizontal = if delta.x > 0.0 { Some(6) } else if delta.x < 0.0 { Some(7) } else { None };
+                    [vertical, horizontal].into_iter().flatten().try_for_each(|button| {
+                        display.pointer_button(button, true)?;
+                        display.pointer_button(button, false)
+                    })
+                }
+                egui::Event::Key { key, pressed, modifiers, .. } if hovered => display.key(key, pressed, modifiers),
+                egui::Event::Text(text) if hovered => display.text(&text),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                eprintln!("Error forwarding input: {}", e);
+            }
+        }
+    }
+
+    // Forward drags on the preview to the filters.
+    // Later stages are drawn on top, so they get the first chance to take a drag.
+    // Positions are mapped through the crops and rotations between a stage and the preview,
+    // so each filter works in the coordinates of the frame it receives.
+    fn handle_preview_drag(&mut self, ui: &egui::Ui, response: &egui::Response) {
+        let image_rect = response.rect;
+        let to_fraction = |pos: egui::Pos2| {
+            let fraction = (pos - image_rect.min) / image_rect.size();
+            egui::pos2(fraction.x.clamp(0.0, 1.0), fraction.y.clamp(0.0, 1.0))
+        };
+
+        // The release frame may no longer report an interaction position
+        let pointer = response.interact_pointer_pos().or_else(|| ui.input(|i| i.pointer.latest_pos()));
+        if let Some(pos) = pointer.map(to_fraction) {
+            if response.drag_started() {
+                let filters = &mut self.filters;
+                self.drag_stage = (0..filters.len()).rev().find(|&index| {
+                    let pos = stage_position(filters, index, pos);
+                    let stage = &mut filters[index];
+                    stage.enabled && stage.filter.preview_drag(PreviewDrag::Start(pos))
+                });
+            } else if let Some(index) = self.drag_stage.filter(|&index| index < self.filters.len()) {
+                let pos = stage_position(&self.filters, index, pos);
+                let drag = if response.drag_stopped() { PreviewDrag::End(pos) } else { PreviewDrag::Move(pos) };
+                self.filters[index].filter.preview_drag(drag);
+            }
+            if self.drag_stage.is_some() {
+                self.pipeline.send(Command::Filters(self.filters.clone()));
+            }
+        }
+        if response.drag_stopped() {
+            self.drag_stage = None;
+        }
+    }
+
+    // Let the filters draw their hints over the preview, e.g. mask outlines and annotations
+    fn paint_preview_hints(&self, ui: &egui::Ui, image_rect: egui::Rect) {
+        let painter = ui.painter_at(image_rect);
+        for (index, stage) in self.filters.iter().enumerate().filter(|(_, stage)| stage.enabled) {
+            let to_screen = |point: egui::Pos2| {
+                let output = self.filters[index..]
+                    .iter()
+                    .filter(|stage| stage.enabled)
+                    .fold(point, |point, stage| stage.filter.map_forward(point));
+                image_rect.min + output.to_vec2() * image_rect.size()
+            };
+            stage.filter.paint_preview(&painter, &to_screen);
         }
     }
 
     // Render method for the Caster mode
     pub fn render(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
         ui.heading("Caster Mode");
+
+        // Take the caster's shortcuts out of the input first, so they never reach a routed app
+        let shortcut = |key| ctx.input_mut(|i| i.consume_key(egui::Modifiers::CTRL, key));
+        let (stream_pressed, blank_pressed, disconnect_pressed) =
+            (shortcut(egui::Key::S), shortcut(egui::Key::B), shortcut(egui::Key::D));
+        // Clearing the annotations works whether or not their settings are open
+        if shortcut(egui::Key::E) {
+            let mut cleared = false;
+            for stage in self.filters.iter_mut() {
+                cleared |= stage.filter.clear_drawings();
+            }
+            if cleared {
+                self.pipeline.send(Command::Filters(self.filters.clone()));
+            }
+        }
+
+        // Display the error message if there is one
+        if let Some(error) = &self.error_message {
+            ui.colored_label(egui::Color32::RED, error);
+        }
         ui.add_space(20.0);
-        // Try to receive a frame from the capture thread
-        if let Some(capture) = &mut self.capture {
-            if let Some(frame) = capture.receive_frame() {
-                self.current_frame = Some(frame.clone());
-                crop(&mut self.current_frame.as_mut().unwrap(), self.crop.clone());
-                blank(&mut self.current_frame.as_mut().unwrap(), self.is_blank);
-                self.server.broadcast_frame(self.current_frame.clone().unwrap(), self.is_streaming);
+        // Show the latest frame processed by the pipeline
+        if self.has_source {
+            if let Some(mut frame) = self.pipeline.preview() {
+                // The preview texture is uploaded as RGBA
+                match frame.convert(PixelFormat::Rgba) {
+                    Ok(()) => self.current_frame = Some(frame),
+                    Err(e) => eprintln!("Cannot show preview frame: {}", e),
+                }
             }
         }
         // display possible screens to capture
-        else {
+        if !self.has_source || self.choosing_source {
+            let mut selected = None;
             for (index, name) in self.displays.iter().enumerate() {
                 if ui.add(egui::Button::new(name)).clicked() {
-                     self.capture = Some(ScreenCapture::new(index).unwrap());
+                    selected = Some(index);
                 }
                 ui.add_space(10.0);
             }
-        }
-        // Display the captured frame (if available)
-        if let Some(frame) = &self.current_frame {
-            ui.columns(4, |columns| {
-                let slider_width = columns[0].available_width() / 1.0; // Width of each slider (columns width)
-            
-                // Left Crop Slider
-                columns[0].vertical(|ui| {
-                    ui.label("Left");
-                    ui.add_sized(
-                        [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.left, 0.0..=100.0),
-                    );
-                });
-            
-                // Right Crop Slider
-                columns[1].vertical(|ui| {
-                    ui.label("Right");
-                    ui.add_sized(
-                        [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.right, 0.0..=100.0),
-                    );
+            // Talk to X directly instead of polling scrap, grabbing only what changed
+            #[cfg(target_os = "linux")]
+            ui.checkbox(&mut self.native_x11, "Native X11 capture (XShm + XDamage)");
+            if let Some(index) = selected {
+                #[cfg(target_os = "linux")]
+                if self.native_x11 {
+                    self.start_capture(X11Capture::new(CaptureTarget::Monitor(index), self.target_fps));
+                } else {
+                    self.start_capture(ScreenCapture::new(index, self.target_fps));
+                }
+                #[cfg(not(target_os = "linux"))]
+                self.start_capture(ScreenCapture::new(index, self.target_fps));
+            }
+
+            // A single application window, followed as it moves and resizes
+            #[cfg(target_os = "linux")]
+            {
+                let mut window_target = None;
+                ui.horizontal(|ui| {
+                    if ui.button("Refresh Windows").clicked() {
+                        match list_windows() {
+                            Ok(windows) => self.windows = windows,
+                            Err(err) => self.error_message = Some(format!("Error: {}", err)),
+                        }
+                    }
+                    if ui.button("Follow Focused Window").clicked() {
+                        window_target = Some(CaptureTarget::FocusedWindow);
+                    }
                 });
-            
-                // Top Crop Slider
-                columns[2].vertical(|ui| {
-                    ui.label("Top");
-                    ui.add_sized(
-                        [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.top, 0.0..=100.0),
-                    );
+                if !self.windows.is_empty() {
+                    egui::ScrollArea::vertical().max_height(150.0).show(ui, |ui| {
+                        for window in &self.windows {
+                            let label = if window.class.is_empty() {
+                                window.title.clone()
+                            } else {
+                                format!("{} ({})", window.title, window.class)
+                            };
+                            if ui.button(label).clicked() {
+                                window_target = Some(CaptureTarget::Window(window.id));
+                            }
+                        }
+                    });
+                }
+                if let Some(target) = window_target {
+                    self.start_capture(X11Capture::new(target, self.target_fps));
+                }
+                ui.add_space(10.0);
+
+                // An app running in its own off-screen display
+                let mut launch = false;
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut self.app_command).hint_text("Command to run in a virtual display"));
+                    ui.add(egui::DragValue::new(&mut self.app_size[0]).range(320..=7680).suffix(" px"));
+                    ui.label("x");
+                    ui.add(egui::DragValue::new(&mut self.app_size[1]).range(240..=4320).suffix(" px"));
+                    if ui.button("Launch App").clicked() {
+                        launch = true;
+                    }
                 });
-            
-                // Bottom Crop Slider
-                columns[3].vertical(|ui| {
-                    ui.label("Bottom");
-                    ui.add_sized(
-                        [slider_width, 20.0],
-                        egui::Slider::new(&mut self.crop.bottom, 0.0..=100.0),
-                    );
+                if launch {
+                    let [width, height] = self.app_size;
+                    match VirtualDisplay::launch(&self.app_command, width, height) {
+                        Ok(display) => {
+                            let name = display.name().to_string();
+                            self.start_capture(X11Capture::on_display(Some(name), CaptureTarget::Screen, self.target_fps));
+                            self.virtual_display = Some(display);
+                        }
+                        Err(err) => self.error_message = Some(format!("Error: {}", err)),
+                    }
+                }
+                ui.add_space(10.0);
+            }
+
+            // Several monitors stitched into one frame following their layout
+            let mut stitched = None;
+            if self.displays.len() > 1 {
+                ui.horizontal(|ui| {
+                    for (name, checked) in self.displays.iter().zip(self.selected_displays.iter_mut()) {
+                        ui.checkbox(checked, name);
+                    }
+                    let any_selected = self.selected_displays.contains(&true);
+                    if ui.add_enabled(any_selected, egui::Button::new("Capture Selected")).clicked() {
+                        let indices = self.selected_displays.iter().enumerate().filter(|(_, checked)| **checked);
+                        stitched = Some(indices.map(|(index, _)| index).collect());
+                    }
+                    if ui.button("All Displays").clicked() {
+                        stitched = Some((0..self.displays.len()).collect());
+                    }
                 });
+                ui.add_space(10.0);
+            }
+            if let Some(indices) = stitched {
+                self.start_capture(ScreenCapture::with_displays(indices, self.target_fps));
+            }
+
+            // Synthetic source, usable without any display attached
+            let mut use_pattern = false;
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.pattern_size[0]).range(64..=7680).suffix(" px"));
+                ui.label("x");
+                ui.add(egui::DragValue::new(&mut self.pattern_size[1]).range(64..=4320).suffix(" px"));
+                ui.add(egui::DragValue::new(&mut self.pattern_fps).range(1..=120).suffix(" fps"));
+                if ui.button("Test Pattern").clicked() {
+                    use_pattern = true;
+                }
+            });
+            if use_pattern {
+                let [width, height] = self.pattern_size;
+                self.set_source(Box::new(TestPattern::new(width, height, self.pattern_fps)));
+            }
+            ui.add_space(10.0);
+
+            // Replay a canned demo instead of a monitor
+            let mut play_media = false;
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.media_path).hint_text("Image folder or .y4m file"));
+                ui.checkbox(&mut self.media_loop, "Loop");
+                if ui.button("Play File").clicked() {
+                    play_media = true;
+                }
             });
+            if play_media {
+                match FilePlayback::open(Path::new(&self.media_path), None, self.media_loop) {
+                    Ok(playback) => {
+                        self.error_message = None;
+                        self.set_source(Box::new(playback));
+                    }
+                    Err(err) => self.error_message = Some(format!("Error: {}", err)),
+                }
+            }
+        }
+        // Display the captured frame (if available)
+        if let Some(frame) = &self.current_frame {
+            // Ordered filter chain: toggle, reorder and tune each stage
+            let mut chain_changed = false;
+            let mut move_up = None;
+            let stage_count = self.filters.len();
+            // Stages pinned first never trade places
+            let pinned: Vec<bool> = self.filters.iter().map(|stage| stage.filter.pinned_first()).collect();
+            let can_swap = |upper: usize| !pinned[upper] && !pinned[upper + 1];
+            for (index, stage) in self.filters.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    chain_changed |= ui.checkbox(&mut stage.enabled, stage.filter.name()).changed();
+                    if ui.add_enabled(index > 0 && can_swap(index - 1), egui::Button::new("▲")).clicked() {
+                        move_up = Some(index);
+                    }
+                    if ui.add_enabled(index + 1 < stage_count && can_swap(index), egui::Button::new("▼")).clicked() {
+                        move_up = Some(index + 1);
+                    }
+                });
+                if stage.enabled {
+                    chain_changed |= stage.filter.ui(ui);
+                }
+            }
+            if let Some(index) = move_up {
+                self.filters.swap(index - 1, index);
+                chain_changed = true;
+            }
+            if chain_changed {
+                self.pipeline.send(Command::Filters(self.filters.clone()));
+            }
             ui.add_space(20.0);
 
             let width = frame.width as usize;
@@ -118,12 +465,106 @@ impl Caster {
             };
 
             // Display the image
-            ui.add(egui::Image::new(&image_handle).fit_to_exact_size(target_size));
+            let response = ui.add(
+                egui::Image::new(&image_handle)
+                    .fit_to_exact_size(target_size)
+                    .sense(egui::Sense::click_and_drag()),
+            );
+            #[cfg(target_os = "linux")]
+            if self.route_input && self.virtual_display.is_some() {
+                self.forward_preview_input(ui, &response);
+            } else {
+                self.handle_preview_drag(ui, &response);
+            }
+            #[cfg(not(target_os = "linux"))]
+            self.handle_preview_drag(ui, &response);
+            self.paint_preview_hints(ui, response.rect);
+
+            // Controls of a launched app
+            #[cfg(target_os = "linux")]
+            if let Some(display) = &mut self.virtual_display {
+                let running = display.is_running();
+                let name = display.name().to_string();
+                ui.horizontal(|ui| {
+                    ui.label(format!("Virtual display {}", name));
+                    ui.checkbox(&mut self.route_input, "Send mouse and keyboard to the app");
+                    if !running {
+                        ui.colored_label(egui::Color32::YELLOW, "The app has exited");
+                    }
+                });
+            }
 
             ui.add_space(10.0);
 
-            let client_count = self.server.get_client_count();
+            let client_count = self.pipeline.get_client_count();
             ui.label(format!("Connected Clients: {}", client_count));
+
+            // Health of the screen capture thread
+            if let Some(state) = &self.capture_state {
+                match &*state.borrow() {
+                    CaptureState::Starting => ui.label("Capture starting..."),
+                    CaptureState::Running => ui.colored_label(egui::Color32::GREEN, "Capture running"),
+                    CaptureState::Restarting { attempt, reason } => ui.colored_label(
+                        egui::Color32::YELLOW,
+                        format!("Capture restarting (attempt {}): {}", attempt, reason),
+                    ),
+                    CaptureState::Failed(reason) => {
+                        ui.colored_label(egui::Color32::RED, format!("Capture failed: {}", reason))
+                    }
+                };
+            }
+
+            // Target rate for capture and broadcast, next to the rate actually achieved
+            ui.horizontal(|ui| {
+                ui.label("Target FPS");
+                if ui.add(egui::DragValue::new(&mut self.target_fps).range(1..=120)).changed() {
+                    self.pipeline.send(Command::Fps(self.target_fps));
+                }
+                ui.label(format!("Achieved: {:.1} fps", self.pipeline.get_achieved_fps()));
+                if let Some(area) = self.pipeline.get_changed_area() {
+                    ui.label(format!("Changed: {:.1}%", area * 100.0));
+                }
+                let change_text = if self.choosing_source { "Keep Current Source" } else { "Change Source" };
+                if ui.button(change_text).clicked() {
+                    self.choosing_source = !self.choosing_source;
+                }
+            });
+
+            // Mouse pointer drawn into captured frames
+            ui.horizontal(|ui| {
+                let previous = self.cursor;
+                ui.checkbox(&mut self.cursor.show, "Show pointer");
+                ui.add_enabled_ui(self.cursor.show, |ui| {
+                    ui.add(egui::DragValue::new(&mut self.cursor.scale).range(0.5..=4.0).speed(0.05).prefix("x"));
+                    ui.checkbox(&mut self.cursor.halo, "Halo");
+                    ui.checkbox(&mut self.cursor.ripples, "Click ripples");
+                });
+                if self.cursor != previous {
+                    self.pipeline.send(Command::Cursor(self.cursor));
+                }
+            });
+
+            // Lossless compression of the stream, next to what it achieves
+            ui.horizontal(|ui| {
+                let previous = self.compression;
+                ui.label("Compression");
+                egui::ComboBox::from_id_source("compression_codec")
+                    .selected_text(self.compression.codec.name())
+                    .show_ui(ui, |ui| {
+                        for codec in Codec::ALL {
+                            ui.selectable_value(&mut self.compression.codec, codec, codec.name());
+                        }
+                    });
+                if self.compression.codec == Codec::Zstd {
+                    ui.add(egui::DragValue::new(&mut self.compression.level).range(ZSTD_LEVELS).prefix("Level "));
+                }
+                if self.compression != previous {
+                    self.pipeline.send(Command::Compression(self.compression));
+                }
+                if let Some((ratio, cost)) = self.pipeline.get_compression_stats() {
+                    ui.label(format!("Ratio: {:.1}:1, {:.1} ms per frame", ratio, cost));
+                }
+            });
     
             ui.add_space(10.0);
     
@@ -131,24 +572,35 @@ impl Caster {
                 // Stream/Pause button with Ctrl+S shortcut in the first column
                 let stream_button_text = if self.is_streaming { "Pause (Ctrl + S)" } else { "Stream (Ctrl + S)" };
                 let stream_button = columns[0].add(egui::Button::new(stream_button_text).fill(egui::Color32::BLUE));
-                if stream_button.clicked() || (ctx.input(|i| i.modifiers.ctrl && i.key_pressed(egui::Key::S))) {
+                if stream_button.clicked() || stream_pressed {
                     self.is_streaming = !self.is_streaming;
+                    self.pipeline.send(Command::Stream(self.is_streaming));
                 }
     
                 // Blank/Stop Blank button with Ctrl+B shortcut in the second column
-                let blank_button_text = if self.is_blank { "Stop Blank (Ctrl + B)" } else { "Blank (Ctrl + B)" };
+                let is_blank = self.is_blank();
+                let blank_button_text = if is_blank { "Stop Blank (Ctrl + B)" } else { "Blank (Ctrl + B)" };
                 let blank_button = columns[1].button(blank_button_text);
-                if blank_button.clicked() || (ctx.input(|i| i.modifiers.ctrl && i.key_pressed(egui::Key::B))) {
-                    self.is_blank = !self.is_blank;
+                if blank_button.clicked() || blank_pressed {
+                    self.set_blank(!is_blank);
                 }
     
                 // Disconnect button with Ctrl+D shortcut in the third column
                 let disconnect_button = columns[2].add(egui::Button::new("Disconnect (Ctrl + D)").fill(egui::Color32::RED));
-                if disconnect_button.clicked() || (ctx.input(|i| i.modifiers.ctrl && i.key_pressed(egui::Key::D))) {
+                if disconnect_button.clicked() || disconnect_pressed {
                     self.is_streaming = false;
-                    self.server.disconnect();
+                    self.pipeline.send(Command::Disconnect);
                 }
             });
         }
     }
-}
\ No newline at end of file
+}
+
+// Position on the preview, in fractions of the output frame, as seen by the stage at `index`
+fn stage_position(filters: &[FilterStage], index: usize, pos: egui::Pos2) -> egui::Pos2 {
+    filters[index..]
+        .iter()
+        .rev()
+        .filter(|stage| stage.enabled)
+        .fold(pos, |pos, stage| stage.filter.map_back(pos))
+}
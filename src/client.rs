@@ -1,14 +1,15 @@
 use tokio::net::TcpStream;
-use tokio::io::{self,AsyncReadExt};
-use tokio::sync::{mpsc,watch};
+use tokio::io::{self,AsyncReadExt,AsyncWriteExt};
+use tokio::sync::{mpsc,oneshot,watch};
 use std::net::SocketAddr;
-use bincode;
-use crate::screen::Frame;
+use crate::compression::{Codec, MAX_FRAME_BYTES, decompress, hello};
+use crate::delta::{FrameUpdate, KEYFRAME_REQUEST};
 use tokio::time::{timeout, Duration};
 
 #[derive(Clone)]
 pub struct DisconnectHandle {
     shutdown_tx: watch::Sender<bool>,
+    keyframe_requests: mpsc::UnboundedSender<()>, // Written to the caster right away
 }
 
 impl DisconnectHandle {
@@ -16,12 +17,17 @@ impl DisconnectHandle {
         // Signal the background task to stop
         let _ = self.shutdown_tx.send(true);
     }
+
+    // Ask the caster for a full frame, when updates no longer apply to what we have
+    pub fn request_keyframe(&self) {
+        let _ = self.keyframe_requests.send(());
+    }
 }
 
 // The function to connect to the server and start receiving frames
 pub async fn connect_to_server(
     ip_address: &str,
-) -> Result<(mpsc::Receiver<Option<Frame>>, DisconnectHandle), String > {
+) -> Result<(mpsc::Receiver<Option<FrameUpdate>>, DisconnectHandle), String > {
     let port = 9041;
     let address_port = format!("{}:{}", ip_address, port);
 
@@ -37,14 +43,35 @@ pub async fn connect_to_server(
 
     println!("Successfully connected to {}", addr);
 
+    // Tell the caster which compressed formats we can decode
+    stream.write_all(&hello()).await.map_err(|e| format!("Handshake with {} failed: {}", addr, e))?;
+
     // Create an MPSC channel to send frames from the receiver task
     let (frame_tx, frame_rx) = mpsc::channel(10);
 
     // Create a watch channel for shutdown signaling
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (keyframe_requests, mut keyframe_rx) = mpsc::unbounded_channel();
+    let (reader_done, mut reader_stopped) = oneshot::channel::<()>();
+    let (mut reader, mut writer) = stream.into_split();
+
+    // Keyframe requests go out as soon as they are made, even while no frame is coming in
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                request = keyframe_rx.recv() => {
+                    if request.is_none() || writer.write_all(&[KEYFRAME_REQUEST]).await.is_err() {
+                        break;
+                    }
+                }
+                _ = &mut reader_stopped => break,
+            }
+        }
+    });
 
     // Spawn a task to handle receiving data from the server
     tokio::spawn(async move {
+        let _reader_done = reader_done; // Stops the request writer when this task ends
         loop {
             // Check for shutdown signal
             if *shutdown_rx.borrow() {
@@ -52,27 +79,36 @@ pub async fn connect_to_server(
             }
 
             let mut size_buffer = [0u8; 4];
-            match stream.read_exact(&mut size_buffer).await {
+            match reader.read_exact(&mut size_buffer).await {
                 Ok(_) => {
                     let frame_size = u32::from_be_bytes(size_buffer) as usize;
                     if frame_size == 0 {
                         continue;
                     }
+                    // The codec tag adds a byte, compression may add a few more
+                    if frame_size > MAX_FRAME_BYTES + 1024 {
+                        eprintln!("Frame of {} bytes is too large, closing the connection", frame_size);
+                        break;
+                    }
 
                     // Step 2: Read the frame data
                     let mut frame_buffer = vec![0u8; frame_size];
-                    match stream.read_exact(&mut frame_buffer).await {
+                    match reader.read_exact(&mut frame_buffer).await {
                         Ok(_) => {
-                            match bincode::deserialize::<Frame>(&frame_buffer) {
-                                Ok(frame) => {
-                                    // Step 4: Send the frame to the main application via the channel
-                                    if frame_tx.send(Some(frame)).await.is_err() {
+                            // A codec tag, then the serialized update compressed with that codec
+                            let decoded = Codec::from_tag(frame_buffer[0])
+                                .and_then(|codec| decompress(codec, &frame_buffer[1..]))
+                                .and_then(|payload| bincode::deserialize::<FrameUpdate>(&payload).map_err(|e| e.to_string()));
+                            match decoded {
+                                Ok(update) => {
+                                    // Step 4: Send the update to the main application via the channel
+                                    if frame_tx.send(Some(update)).await.is_err() {
                                         // If the receiver side is closed, stop the loop
                                         break;
                                     }
                                 }
                                 Err(e) => {
-                                    eprintln!("Failed to deserialize frame: {}", e);
+                                    eprintln!("Failed to decode frame: {}", e);
                                     break;
                                 }
                             }
@@ -103,17 +139,11 @@ pub async fn connect_to_server(
                 }
             }
         }
-        if let Ok(stream_std) = stream.into_std() {
-            use std::net::Shutdown;
-            if let Err(e) = stream_std.shutdown(Shutdown::Both) {
-                eprintln!("Error shutting down the connection: {}", e);
-            }
-            drop(stream_std);
-        }
+        // The connection closes once the request writer drops its half too
         println!("Receiver task exiting.");
     });
 
     // Return the frame receiver and disconnect handle to the caller
-    let disconnect_handle = DisconnectHandle { shutdown_tx };
+    let disconnect_handle = DisconnectHandle { shutdown_tx, keyframe_requests };
     Ok((frame_rx, disconnect_handle))
 }
\ No newline at end of file
@@ -0,0 +1,198 @@
+use std::borrow::Cow;
+use std::ops::RangeInclusive;
+use serde::{Deserialize, Serialize};
+
+// Lossless compression of the serialized frames sent to receivers
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum Codec {
+    None,
+    Lz4,  // Fast, moderate ratio
+    Zstd, // Better ratio, cost depends on the level
+}
+
+impl Codec {
+    pub const ALL: [Codec; 3] = [Codec::None, Codec::Lz4, Codec::Zstd];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "None",
+            Codec::Lz4 => "LZ4",
+            Codec::Zstd => "Zstd",
+        }
+    }
+
+    // Byte leading every frame message, telling how its payload is compressed
+    pub fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self, String> {
+        Codec::ALL.into_iter().find(|codec| codec.tag() == tag).ok_or_else(|| format!("Unknown compression tag {}", tag))
+    }
+
+    // Bit of this codec in the receiver hello
+    fn bit(self) -> u8 {
+        1 << self.tag()
+    }
+}
+
+pub const ZSTD_LEVELS: RangeInclusive<i32> = 1..=19;
+
+// Largest serialized update a receiver accepts, room for an 8K RGBA frame. Sizes come from
+// the network, so anything bigger is treated as a broken stream rather than allocated.
+pub const MAX_FRAME_BYTES: usize = 256 << 20;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CompressionSettings {
+    pub codec: Codec,
+    pub level: i32, // Only used by Zstd
+}
+
+impl CompressionSettings {
+    pub fn new() -> Self {
+        Self { codec: Codec::Lz4, level: 3 }
+    }
+}
+
+// Sent by receivers right after connecting: magic, protocol version and the codecs they decode
+const HELLO_MAGIC: [u8; 2] = *b"US";
+const PROTOCOL_VERSION: u8 = 3;
+pub const HELLO_LEN: usize = 4;
+
+pub fn hello() -> [u8; HELLO_LEN] {
+    let codecs = Codec::ALL.iter().fold(0, |bits, codec| bits | codec.bit());
+    [HELLO_MAGIC[0], HELLO_MAGIC[1], PROTOCOL_VERSION, codecs]
+}
+
+// Codecs a receiver accepts according to its hello; uncompressed frames always work.
+// Receivers speaking another protocol version couldn't decode our frames and are refused.
+pub fn accepted_codecs(hello: &[u8; HELLO_LEN]) -> Result<Vec<Codec>, String> {
+    if hello[..2] != HELLO_MAGIC {
+        return Err("Not a UStream receiver".to_string());
+    }
+    if hello[2] != PROTOCOL_VERSION {
+        return Err(format!("Receiver speaks protocol version {}, expected {}", hello[2], PROTOCOL_VERSION));
+    }
+    let mut codecs = vec![Codec::None];
+    codecs.extend(Codec::ALL.into_iter().filter(|codec| *codec != Codec::None && hello[3] & codec.bit() != 0));
+    Ok(codecs)
+}
+
+pub fn compress(data: &[u8], settings: CompressionSettings) -> Result<Vec<u8>, String> {
+    match settings.codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        Codec::Zstd => {
+            let level = settings.level.clamp(*ZSTD_LEVELS.start(), *ZSTD_LEVELS.end());
+            zstd::bulk::compress(data, level).map_err(|e| format!("Zstd compression failed: {}", e))
+        }
+    }
+}
+
+pub fn decompress(codec: Codec, data: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    match codec {
+        Codec::None => Ok(Cow::Borrowed(data)),
+        Codec::Lz4 => {
+            // Little-endian size prefix written by compress_prepend_size
+            let (size, block) = data.split_first_chunk::<4>().ok_or("Truncated LZ4 frame")?;
+            let size = check_frame_size(u32::from_le_bytes(*size) as u64)?;
+            lz4_flex::decompress(block, size).map(Cow::Owned).map_err(|e| format!("Invalid LZ4 frame: {}", e))
+        }
+        Codec::Zstd => {
+            let size = zstd::zstd_safe::get_frame_content_size(data)
+                .map_err(|_| "Invalid Zstd frame header".to_string())?
+                .ok_or("Zstd frame without a content size")?;
+            let size = check_frame_size(size)?;
+            zstd::bulk::decompress(data, size).map(Cow::Owned).map_err(|e| format!("Invalid Zstd frame: {}", e))
+        }
+    }
+}
+
+fn check_frame_size(size: u64) -> Result<usize, String> {
+    if size > MAX_FRAME_BYTES as u64 {
+        return Err(format!("Frame of {} bytes exceeds the {} byte limit", size, MAX_FRAME_BYTES));
+    }
+    Ok(size as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i / 64 % 7) as u8).collect()
+    }
+
+    #[test]
+    fn hello_offers_every_codec() {
+        assert_eq!(accepted_codecs(&hello()).unwrap(), Codec::ALL);
+    }
+
+    #[test]
+    fn uncompressed_is_always_accepted() {
+        let mut message = hello();
+        message[3] = 0;
+        assert_eq!(accepted_codecs(&message).unwrap(), [Codec::None]);
+        message[3] = Codec::Zstd.bit() | 0x80;
+        assert_eq!(accepted_codecs(&message).unwrap(), [Codec::None, Codec::Zstd]);
+    }
+
+    #[test]
+    fn refuses_other_receivers() {
+        let mut message = hello();
+        message[0] = b'X';
+        assert_eq!(accepted_codecs(&message).unwrap_err(), "Not a UStream receiver");
+        assert!(accepted_codecs(b"GET ").is_err());
+
+        let mut message = hello();
+        message[2] = PROTOCOL_VERSION - 1;
+        assert!(accepted_codecs(&message).is_err());
+        message[2] = PROTOCOL_VERSION + 1;
+        assert!(accepted_codecs(&message).is_err());
+    }
+
+    #[test]
+    fn round_trips_every_codec() {
+        let data = sample();
+        for codec in Codec::ALL {
+            let compressed = compress(&data, CompressionSettings { codec, level: 3 }).unwrap();
+            assert_eq!(decompress(codec, &compressed).unwrap(), data.as_slice(), "{}", codec.name());
+        }
+        // Out of range levels are clamped rather than refused
+        let compressed = compress(&data, CompressionSettings { codec: Codec::Zstd, level: 100 }).unwrap();
+        assert_eq!(decompress(Codec::Zstd, &compressed).unwrap(), data.as_slice());
+    }
+
+    #[test]
+    fn rejects_oversized_lz4_frames() {
+        let mut compressed = lz4_flex::compress_prepend_size(&sample());
+        compressed[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decompress(Codec::Lz4, &compressed).unwrap_err().contains("limit"));
+        assert!(decompress(Codec::Lz4, &[1, 0]).is_err());
+    }
+
+    #[test]
+    fn rejects_unbounded_zstd_frames() {
+        let data = sample();
+        // The streaming encoder doesn't record the content size
+        let mut streamed = Vec::new();
+        let mut encoder = zstd::stream::write::Encoder::new(&mut streamed, 3).unwrap();
+        std::io::Write::write_all(&mut encoder, &data).unwrap();
+        encoder.finish().unwrap();
+        assert!(decompress(Codec::Zstd, &streamed).is_err());
+
+        assert!(decompress(Codec::Zstd, b"not zstd").is_err());
+    }
+
+    #[test]
+    fn unknown_tags_are_errors() {
+        for codec in Codec::ALL {
+            assert_eq!(Codec::from_tag(codec.tag()).unwrap(), codec);
+        }
+        assert!(Codec::from_tag(3).is_err());
+    }
+}
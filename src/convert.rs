@@ -0,0 +1,357 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Below this many pixels splitting the work across threads costs more than it saves
+const PARALLEL_THRESHOLD: usize = 256 * 256;
+
+// Convert a BGRA/BGRX image with `src_stride` bytes per row into RGBA rows of `dst`,
+// `dst_stride` bytes apart. Rows are converted in parallel and alpha is forced to 255.
+pub fn bgra_to_rgba_rows(src: &[u8], src_stride: usize, dst: &mut [u8], dst_stride: usize, width: usize, height: usize) {
+    let row_len = width * 4;
+    if width == 0 || height == 0 {
+        return;
+    }
+    assert!(src_stride >= row_len && dst_stride >= row_len, "Stride shorter than a row");
+    assert!(src.len() >= src_stride * (height - 1) + row_len, "Source image too small");
+    assert!(dst.len() >= dst_stride * (height - 1) + row_len, "Destination image too small");
+
+    let dst = &mut dst[..dst_stride * (height - 1) + row_len];
+    let convert = |(y, dst_row): (usize, &mut [u8])| {
+        let src_row = &src[y * src_stride..y * src_stride + row_len];
+        swizzle_row(src_row, &mut dst_row[..row_len]);
+    };
+    if width * height >= PARALLEL_THRESHOLD {
+        dst.par_chunks_mut(dst_stride).enumerate().for_each(convert);
+    } else {
+        dst.chunks_mut(dst_stride).enumerate().for_each(convert);
+    }
+}
+
+// Copy `rows` rows of `row_len` bytes between images with different row padding
+pub fn copy_rows(src: &[u8], src_stride: usize, dst: &mut [u8], dst_stride: usize, row_len: usize, rows: usize) {
+    if rows == 0 || row_len == 0 {
+        return;
+    }
+    if src_stride == row_len && dst_stride == row_len {
+        dst[..row_len * rows].copy_from_slice(&src[..row_len * rows]);
+        return;
+    }
+    for (dst_row, src_row) in dst.chunks_mut(dst_stride).zip(src.chunks(src_stride)).take(rows) {
+        dst_row[..row_len].copy_from_slice(&src_row[..row_len]);
+    }
+}
+
+// Swap blue and red a pixel at a time on 32-bit words, a loop the compiler vectorizes
+fn swizzle_row(src: &[u8], dst: &mut [u8]) {
+    for (dst, src) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        let pixel = u32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+        let rgba = ((pixel >> 16) & 0xff) | (pixel & 0xff00) | ((pixel & 0xff) << 16) | 0xff00_0000;
+        dst.copy_from_slice(&rgba.to_le_bytes());
+    }
+}
+
+// Memory layout of a frame. Planar formats keep their chroma at half resolution in both
+// directions, after the full-size luma plane; `stride` always refers to the first plane.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    Bgra,   // 4 bytes per pixel, what screen capture produces; alpha is ignored
+    Rgba,   // 4 bytes per pixel, what egui textures and most filters want
+    Rgb,    // 3 bytes per pixel
+    Yuv420, // BT.601 limited range: Y plane, then U and V planes
+    Nv12,   // BT.601 limited range: Y plane, then one plane of interleaved U/V pairs
+}
+
+impl PixelFormat {
+    pub const ALL: [PixelFormat; 5] = [PixelFormat::Bgra, PixelFormat::Rgba, PixelFormat::Rgb, PixelFormat::Yuv420, PixelFormat::Nv12];
+
+    // Bytes per row of the first plane when rows are not padded
+    pub fn packed_stride(self, width: u32) -> usize {
+        let width = width as usize;
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba => width * 4,
+            PixelFormat::Rgb => width * 3,
+            PixelFormat::Yuv420 => width,
+            PixelFormat::Nv12 => width.next_multiple_of(2), // Room for the last U/V pair
+        }
+    }
+
+    // Size of a pixel for formats storing whole pixels side by side, None for planar ones
+    pub fn bytes_per_pixel(self) -> Option<usize> {
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba => Some(4),
+            PixelFormat::Rgb => Some(3),
+            PixelFormat::Yuv420 | PixelFormat::Nv12 => None,
+        }
+    }
+
+    // Bytes needed for an image whose first plane rows are `stride` bytes apart
+    pub fn buffer_len(self, stride: usize, height: u32) -> usize {
+        let height = height as usize;
+        let chroma_height = height.div_ceil(2);
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba | PixelFormat::Rgb => stride * height,
+            PixelFormat::Yuv420 => stride * height + 2 * stride.div_ceil(2) * chroma_height,
+            PixelFormat::Nv12 => stride * height + stride * chroma_height,
+        }
+    }
+}
+
+// Convert an image in any format into tightly packed RGBA
+pub fn to_rgba(src: &[u8], format: PixelFormat, stride: usize, width: u32, height: u32) -> Result<Vec<u8>, String> {
+    check_size(src, format, stride, width, height)?;
+    let (w, h) = (width as usize, height as usize);
+    let mut dst = vec![0u8; w * h * 4];
+    if w == 0 || h == 0 {
+        return Ok(dst);
+    }
+    let chroma_stride = stride.div_ceil(2);
+    let luma_len = stride * h;
+
+    match format {
+        PixelFormat::Rgba => copy_rows(src, stride, &mut dst, w * 4, w * 4, h),
+        PixelFormat::Bgra => bgra_to_rgba_rows(src, stride, &mut dst, w * 4, w, h),
+        PixelFormat::Rgb => for_each_row(&mut dst, w * 4, |y, dst_row| {
+            for (dst, src) in dst_row.chunks_exact_mut(4).zip(src[y * stride..].chunks_exact(3)) {
+                dst.copy_from_slice(&[src[0], src[1], src[2], 255]);
+            }
+        }),
+        PixelFormat::Yuv420 | PixelFormat::Nv12 => for_each_row(&mut dst, w * 4, |y, dst_row| {
+            let luma = &src[y * stride..y * stride + w];
+            for (x, (dst, &luma)) in dst_row.chunks_exact_mut(4).zip(luma).enumerate() {
+                let (u, v) = if format == PixelFormat::Yuv420 {
+                    let index = (y / 2) * chroma_stride + x / 2;
+                    let plane = chroma_stride * h.div_ceil(2);
+                    (src[luma_len + index], src[luma_len + plane + index])
+                } else {
+                    let index = luma_len + (y / 2) * stride + (x / 2) * 2;
+                    (src[index], src[index + 1])
+                };
+                dst.copy_from_slice(&yuv_to_rgba(luma, u, v));
+            }
+        }),
+    }
+    Ok(dst)
+}
+
+// Convert tightly packed RGBA into `format`, with unpadded rows. Chroma is averaged
+// over each 2x2 block of pixels.
+pub fn from_rgba(rgba: &[u8], width: u32, height: u32, format: PixelFormat) -> Result<Vec<u8>, String> {
+    check_size(rgba, PixelFormat::Rgba, width as usize * 4, width, height)?;
+    let (w, h) = (width as usize, height as usize);
+    let stride = format.packed_stride(width);
+    let mut dst = vec![0u8; format.buffer_len(stride, height)];
+    if w == 0 || h == 0 {
+        return Ok(dst);
+    }
+
+    match format {
+        PixelFormat::Rgba => dst.copy_from_slice(rgba),
+        PixelFormat::Bgra => bgra_to_rgba_rows(rgba, w * 4, &mut dst, w * 4, w, h), // Same swap both ways
+        PixelFormat::Rgb => for_each_row(&mut dst, stride, |y, dst_row| {
+            for (dst, src) in dst_row.chunks_exact_mut(3).zip(rgba[y * w * 4..].chunks_exact(4)) {
+                dst.copy_from_slice(&src[..3]);
+            }
+        }),
+        PixelFormat::Yuv420 | PixelFormat::Nv12 => {
+            let (luma, chroma) = dst.split_at_mut(stride * h);
+            for_each_row(luma, stride, |y, luma_row| {
+                for (dst, src) in luma_row.iter_mut().zip(rgba[y * w * 4..(y + 1) * w * 4].chunks_exact(4)) {
+                    *dst = rgb_to_y(src[0], src[1], src[2]);
+                }
+            });
+
+            let chroma_width = w.div_ceil(2);
+            let block_chroma = |cx: usize, cy: usize| {
+                let mut sum = [0u32; 3];
+                let mut count = 0;
+                for y in cy * 2..(cy * 2 + 2).min(h) {
+                    for x in cx * 2..(cx * 2 + 2).min(w) {
+                        let pixel = &rgba[(y * w + x) * 4..];
+                        for (total, value) in sum.iter_mut().zip(pixel) {
+                            *total += *value as u32;
+                        }
+                        count += 1;
+                    }
+                }
+                let [r, g, b] = sum.map(|total| ((total + count / 2) / count) as u8);
+                rgb_to_uv(r, g, b)
+            };
+            if format == PixelFormat::Yuv420 {
+                let plane = chroma_width * h.div_ceil(2);
+                let (u_plane, v_plane) = chroma.split_at_mut(plane);
+                for (cy, (u_row, v_row)) in u_plane.chunks_exact_mut(chroma_width).zip(v_plane.chunks_exact_mut(chroma_width)).enumerate() {
+                    for (cx, (u, v)) in u_row.iter_mut().zip(v_row.iter_mut()).enumerate() {
+                        (*u, *v) = block_chroma(cx, cy);
+                    }
+                }
+            } else {
+                for_each_row(chroma, stride, |cy, uv_row| {
+                    for (cx, pair) in uv_row.chunks_exact_mut(2).take(chroma_width).enumerate() {
+                        let (u, v) = block_chroma(cx, cy);
+                        pair.copy_from_slice(&[u, v]);
+                    }
+                });
+            }
+        }
+    }
+    Ok(dst)
+}
+
+fn check_size(data: &[u8], format: PixelFormat, stride: usize, width: u32, height: u32) -> Result<(), String> {
+    if stride < format.packed_stride(width) {
+        return Err(format!("Stride {} too short for {} {:?} pixels", stride, width, format));
+    }
+    let needed = match format.bytes_per_pixel() {
+        // The last row of a packed image needs no padding, so parts of a frame convert in place
+        Some(bpp) if height > 0 => stride * (height as usize - 1) + width as usize * bpp,
+        _ => format.buffer_len(stride, height),
+    };
+    if data.len() < needed {
+        return Err(format!("{:?} image of {}x{} needs {} bytes, got {}", format, width, height, needed, data.len()));
+    }
+    Ok(())
+}
+
+// Run `convert` on each `stride`-sized row of `dst`, in parallel for large images
+fn for_each_row<F>(dst: &mut [u8], stride: usize, convert: F)
+where
+    F: Fn(usize, &mut [u8]) + Sync,
+{
+    if stride == 0 {
+        return;
+    }
+    if dst.len() >= PARALLEL_THRESHOLD * 4 {
+        dst.par_chunks_mut(stride).enumerate().for_each(|(y, row)| convert(y, row));
+    } else {
+        dst.chunks_mut(stride).enumerate().for_each(|(y, row)| convert(y, row));
+    }
+}
+
+// BT.601 limited-range YUV to RGBA
+pub fn yuv_to_rgba(y: u8, u: u8, v: u8) -> [u8; 4] {
+    let c = y as i32 - 16;
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let r = (298 * c + 409 * e + 128) >> 8;
+    let g = (298 * c - 100 * d - 208 * e + 128) >> 8;
+    let b = (298 * c + 516 * d + 128) >> 8;
+    [r.clamp(0, 255) as u8, g.clamp(0, 255) as u8, b.clamp(0, 255) as u8, 255]
+}
+
+fn rgb_to_y(r: u8, g: u8, b: u8) -> u8 {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8
+}
+
+fn rgb_to_uv(r: u8, g: u8, b: u8) -> (u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (u as u8, v as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Smooth gradient, so chroma subsampling loses little
+    fn gradient(width: u32, height: u32) -> Vec<u8> {
+        let wave = |v: u32| 30 + (v % 180).min(180 - v % 180) as u8;
+        (0..height).flat_map(|y| (0..width).flat_map(move |x| [wave(x * 3), wave(y * 3 + 50), 128, 255])).collect()
+    }
+
+    // Copy an unpadded image into one whose first plane rows are `stride` bytes apart
+    fn pad(data: &[u8], format: PixelFormat, width: u32, height: u32, stride: usize) -> Vec<u8> {
+        let packed = format.packed_stride(width);
+        let h = height as usize;
+        let chroma_rows = h.div_ceil(2);
+        // (row length, rows, source stride, destination stride) of each plane
+        let planes = match format {
+            PixelFormat::Yuv420 => {
+                let chroma = (packed.div_ceil(2), chroma_rows, packed.div_ceil(2), stride.div_ceil(2));
+                vec![(packed, h, packed, stride), chroma, chroma]
+            }
+            PixelFormat::Nv12 => vec![(packed, h, packed, stride), (packed, chroma_rows, packed, stride)],
+            _ => vec![(packed, h, packed, stride)],
+        };
+        let mut padded = vec![0xAA; format.buffer_len(stride, height)];
+        let (mut src, mut dst) = (0, 0);
+        for (row_len, rows, src_stride, dst_stride) in planes {
+            for y in 0..rows {
+                padded[dst + y * dst_stride..][..row_len].copy_from_slice(&data[src + y * src_stride..][..row_len]);
+            }
+            src += src_stride * rows;
+            dst += dst_stride * rows;
+        }
+        padded
+    }
+
+    fn max_error(a: &[u8], b: &[u8]) -> u8 {
+        assert_eq!(a.len(), b.len());
+        a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0)
+    }
+
+    fn round_trip(format: PixelFormat, tolerance: u8) {
+        for (width, height) in [(1, 1), (2, 2), (3, 5), (17, 9), (64, 48), (301, 199)] {
+            let rgba = gradient(width, height);
+            let converted = from_rgba(&rgba, width, height, format).unwrap();
+            let stride = format.packed_stride(width);
+            assert_eq!(converted.len(), format.buffer_len(stride, height));
+
+            let back = to_rgba(&converted, format, stride, width, height).unwrap();
+            let error = max_error(&rgba, &back);
+            assert!(error <= tolerance, "{:?} {}x{}: error {}", format, width, height, error);
+
+            let padded = pad(&converted, format, width, height, stride + 6);
+            let from_padded = to_rgba(&padded, format, stride + 6, width, height).unwrap();
+            assert_eq!(from_padded, back, "{:?} {}x{} with padded rows", format, width, height);
+        }
+    }
+
+    #[test]
+    fn rgba_round_trip() {
+        round_trip(PixelFormat::Rgba, 0);
+    }
+
+    #[test]
+    fn bgra_round_trip() {
+        round_trip(PixelFormat::Bgra, 0);
+    }
+
+    #[test]
+    fn rgb_round_trip() {
+        round_trip(PixelFormat::Rgb, 0);
+    }
+
+    #[test]
+    fn yuv420_round_trip() {
+        round_trip(PixelFormat::Yuv420, 4);
+    }
+
+    #[test]
+    fn nv12_round_trip() {
+        round_trip(PixelFormat::Nv12, 4);
+    }
+
+    #[test]
+    fn bgra_swaps_red_and_blue() {
+        let bgra = from_rgba(&[10, 20, 30, 255, 40, 50, 60, 255], 2, 1, PixelFormat::Bgra).unwrap();
+        assert_eq!(bgra, [30, 20, 10, 255, 60, 50, 40, 255]);
+    }
+
+    #[test]
+    fn yuv_reference_colors() {
+        // Black, white, red, green and blue in BT.601 limited range
+        let rgba = [0, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255];
+        let yuv = from_rgba(&rgba, 5, 1, PixelFormat::Yuv420).unwrap();
+        assert_eq!(yuv[..5], [16, 235, 82, 144, 41]);
+    }
+
+    #[test]
+    fn rejects_short_buffers() {
+        assert!(to_rgba(&[0; 15], PixelFormat::Rgba, 8, 2, 2).is_err());
+        assert!(to_rgba(&[0; 16], PixelFormat::Rgba, 7, 2, 2).is_err());
+        assert!(to_rgba(&[0; 5], PixelFormat::Yuv420, 2, 2, 2).is_err());
+        assert!(from_rgba(&[0; 12], 2, 2, PixelFormat::Nv12).is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::dirty::TILE_SIZE;
+use crate::screen::{DamageRect, Frame};
+
+// A full frame is sent at least this often, whatever receivers ask for
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(5);
+
+// Byte a receiver writes to the caster when it needs a full frame to resync
+pub const KEYFRAME_REQUEST: u8 = b'K';
+
+// What goes on the wire for each frame: the whole frame, for planar formats its XOR with the
+// previous one, or for packed formats the tiles that changed since the previous one. Unchanged pixels XOR to
+// zero, which the stream compression squeezes to almost nothing.
+#[derive(Serialize, Deserialize)]
+pub enum FrameUpdate {
+    Key { sequence: u64, frame: Frame },
+    Delta { sequence: u64, xor: Vec<u8> },
+    Tiles { sequence: u64, tiles: Vec<Tile> }, // New variants go last, bincode sends the index
+}
+
+// Changed area of a packed frame, rows stored without padding
+#[derive(Serialize, Deserialize)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+// Turns the frames sent by the caster into updates, remembering the last one
+pub struct DeltaEncoder {
+    previous: Option<Frame>,
+    sequence: u64,
+    last_keyframe: Instant,
+}
+
+impl DeltaEncoder {
+    pub fn new() -> Self {
+        Self { previous: None, sequence: 0, last_keyframe: Instant::now() }
+    }
+
+    // Make the next update a keyframe
+    pub fn request_keyframe(&mut self) {
+        self.previous = None;
+    }
+
+    pub fn encode(&mut self, frame: Frame) -> FrameUpdate {
+        self.sequence += 1;
+        let sequence = self.sequence;
+        let base = self.previous.take().filter(|previous| {
+            same_layout(previous, &frame) && self.last_keyframe.elapsed() < KEYFRAME_INTERVAL
+        });
+        match base {
+            Some(previous) => {
+                let tiled = frame.format.bytes_per_pixel().filter(|&bpp| {
+                    frame.stride >= frame.width as usize * bpp && frame.data.len() >= frame.stride * frame.height as usize
+                });
+                let update = match tiled {
+                    Some(bpp) => {
+                        // The damage tracked by the caster says which tiles changed, comparing is the fallback
+                        let tiles = match &frame.damage {
+                            Some(damage) => damaged_tiles(&frame, damage, bpp),
+                            None => changed_tiles(&previous, &frame, bpp),
+                        };
+                        FrameUpdate::Tiles { sequence, tiles }
+                    }
+                    None => FrameUpdate::Delta { sequence, xor: xor(&previous.data, &frame.data) },
+                };
+                self.previous = Some(frame);
+                update
+            }
+            None => {
+                self.last_keyframe = Instant::now();
+                self.previous = Some(frame.clone());
+                FrameUpdate::Key { sequence, frame }
+            }
+        }
+    }
+}
+
+// Rebuilds frames on the receiver side, patching one persistent framebuffer
+pub struct DeltaDecoder {
+    current: Option<Frame>,
+    sequence: u64,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self { current: None, sequence: 0 }
+    }
+
+    // Framebuffer holding the latest picture
+    pub fn frame(&self) -> Option<&Frame> {
+        self.current.as_ref()
+    }
+
+    // Apply `update` to the framebuffer. Returns the areas it patched, or None when the whole
+    // frame was replaced. An update that doesn't follow the last frame we have is an error;
+    // the receiver must ask for a keyframe and wait for it.
+    pub fn apply(&mut self, update: FrameUpdate) -> Result<Option<Vec<DamageRect>>, String> {
+        let (sequence, current) = match update {
+            FrameUpdate::Key { sequence, frame } => {
+                self.sequence = sequence;
+                self.current = Some(frame);
+                return Ok(None);
+            }
+            FrameUpdate::Tiles { sequence, .. } | FrameUpdate::Delta { sequence, .. } => {
+                let Some(current) = &mut self.current else {
+                    return Err("Update before any keyframe".to_string());
+                };
+                if sequence != self.sequence + 1 {
+                    self.current = None;
+                    return Err(format!("Expected frame {}, got {}", self.sequence + 1, sequence));
+                }
+                (sequence, current)
+            }
+        };
+
+        let patched = match update {
+            FrameUpdate::Tiles { tiles, .. } => patch_tiles(current, &tiles).map(Some),
+            FrameUpdate::Delta { xor, .. } if xor.len() == current.data.len() => {
+                for (pixel, change) in current.data.iter_mut().zip(&xor) {
+                    *pixel ^= change;
+                }
+                Ok(None)
+            }
+            _ => Err("Delta frame does not match the current frame size".to_string()),
+        };
+        match patched {
+            Ok(patched) => {
+                self.sequence = sequence;
+                Ok(patched)
+            }
+            Err(e) => {
+                self.current = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn same_layout(a: &Frame, b: &Frame) -> bool {
+    a.width == b.width && a.height == b.height && a.format == b.format && a.stride == b.stride && a.data.len() == b.data.len()
+}
+
+fn xor(previous: &[u8], current: &[u8]) -> Vec<u8> {
+    previous.iter().zip(current).map(|(a, b)| a ^ b).collect()
+}
+
+// Tiles of `frame` that differ from `previous`, compared in parallel
+fn changed_tiles(previous: &Frame, frame: &Frame, bpp: usize) -> Vec<Tile> {
+    (0..tile_count(frame))
+        .into_par_iter()
+        .filter(|&index| {
+            let (rows, bytes) = tile_bounds(frame, index, bpp);
+            let row = |y: usize| y * frame.stride + bytes.start..y * frame.stride + bytes.end;
+            rows.into_iter().any(|y| previous.data[row(y)] != frame.data[row(y)])
+        })
+        .map(|index| cut_tile(frame, index, bpp))
+        .collect()
+}
+
+// Tiles of `frame` touched by the damage rectangles
+fn damaged_tiles(frame: &Frame, damage: &[DamageRect], bpp: usize) -> Vec<Tile> {
+    let tiles_x = frame.width.div_ceil(TILE_SIZE);
+    let tiles_y = frame.height.div_ceil(TILE_SIZE);
+    let mut damaged = vec![false; tile_count(frame)];
+    for rect in damage {
+        let right = rect.x.saturating_add(rect.width).div_ceil(TILE_SIZE).min(tiles_x);
+        let bottom = rect.y.saturating_add(rect.height).div_ceil(TILE_SIZE).min(tiles_y);
+        for ty in rect.y / TILE_SIZE..bottom {
+            for tx in rect.x / TILE_SIZE..right {
+                damaged[(ty * tiles_x + tx) as usize] = true;
+            }
+        }
+    }
+    (0..damaged.len())
+        .into_par_iter()
+        .filter(|&index| damaged[index])
+        .map(|index| cut_tile(frame, index, bpp))
+        .collect()
+}
+
+fn tile_count(frame: &Frame) -> usize {
+    frame.width.div_ceil(TILE_SIZE) as usize * frame.height.div_ceil(TILE_SIZE) as usize
+}
+
+// Rows and byte columns covered by the tile at `index`, tiles numbered row by row
+fn tile_bounds(frame: &Frame, index: usize, bpp: usize) -> (Range<usize>, Range<usize>) {
+    let tile = TILE_SIZE as usize;
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let tiles_x = width.div_ceil(tile);
+    let (x, y) = (index % tiles_x * tile, index / tiles_x * tile);
+    (y..(y + tile).min(height), x * bpp..(x + tile).min(width) * bpp)
+}
+
+fn cut_tile(frame: &Frame, index: usize, bpp: usize) -> Tile {
+    let (rows, bytes) = tile_bounds(frame, index, bpp);
+    let mut data = Vec::with_capacity(rows.len() * bytes.len());
+    for y in rows.clone() {
+        data.extend_from_slice(&frame.data[y * frame.stride + bytes.start..y * frame.stride + bytes.end]);
+    }
+    Tile {
+        x: (bytes.start / bpp) as u32,
+        y: rows.start as u32,
+        width: (bytes.len() / bpp) as u32,
+        height: rows.len() as u32,
+        data,
+    }
+}
+
+fn patch_tiles(frame: &mut Frame, tiles: &[Tile]) -> Result<Vec<DamageRect>, String> {
+    let bpp = frame.format.bytes_per_pixel().ok_or("Tile update for a planar frame")?;
+    if frame.stride < frame.width as usize * bpp || frame.data.len() < frame.stride * frame.height as usize {
+        return Err("Frame too small for its size".to_string());
+    }
+    let mut patched = Vec::with_capacity(tiles.len());
+    for tile in tiles {
+        let fits = tile.x.checked_add(tile.width).is_some_and(|right| right <= frame.width)
+            && tile.y.checked_add(tile.height).is_some_and(|bottom| bottom <= frame.height)
+            && tile.data.len() == tile.width as usize * tile.height as usize * bpp;
+        if !fits {
+            return Err(format!("Tile at {},{} does not fit the frame", tile.x, tile.y));
+        }
+        let row_len = tile.width as usize * bpp;
+        if row_len == 0 {
+            continue;
+        }
+        for (y, row) in (tile.y as usize..).zip(tile.data.chunks_exact(row_len)) {
+            let start = y * frame.stride + tile.x as usize * bpp;
+            frame.data[start..start + row_len].copy_from_slice(row);
+        }
+        patched.push(DamageRect { x: tile.x, y: tile.y, width: tile.width, height: tile.height });
+    }
+    Ok(patched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::PixelFormat;
+
+    fn gradient(width: u32, height: u32, format: PixelFormat) -> Frame {
+        let len = format.buffer_len(format.packed_stride(width), height);
+        Frame::new((0..len).map(|i| (i % 251) as u8).collect(), width, height, format)
+    }
+
+    fn tile(x: u32, y: u32, width: u32, height: u32, bpp: usize) -> Tile {
+        Tile { x, y, width, height, data: vec![7; width as usize * height as usize * bpp] }
+    }
+
+    // Encode every frame and check the decoder rebuilds each one exactly
+    fn round_trip(frames: Vec<Frame>) {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        for frame in frames {
+            let expected = frame.data.clone();
+            decoder.apply(encoder.encode(frame)).unwrap();
+            assert_eq!(decoder.frame().unwrap().data, expected);
+        }
+    }
+
+    #[test]
+    fn round_trips_packed_frames() {
+        let first = gradient(150, 100, PixelFormat::Rgba);
+        let mut second = first.clone();
+        second.data[(70 * 150 + 130) * 4] ^= 0xff;
+        let mut third = second.clone();
+        third.data[3] ^= 0xff;
+        round_trip(vec![first, second, third]);
+    }
+
+    #[test]
+    fn sends_only_changed_tiles() {
+        let mut encoder = DeltaEncoder::new();
+        let first = gradient(150, 100, PixelFormat::Rgba);
+        let mut second = first.clone();
+        encoder.encode(first);
+        second.data[(70 * 150 + 130) * 4] ^= 0xff;
+        let FrameUpdate::Tiles { tiles, .. } = encoder.encode(second) else {
+            panic!("Expected a tile update");
+        };
+        assert_eq!(tiles.len(), 1);
+        assert_eq!((tiles[0].x, tiles[0].y, tiles[0].width, tiles[0].height), (128, 64, 22, 36));
+    }
+
+    #[test]
+    fn round_trips_tracked_damage() {
+        let first = gradient(150, 100, PixelFormat::Bgra);
+        let mut second = first.clone();
+        second.data[(10 * 150 + 10) * 4] ^= 0xff;
+        second.data[(99 * 150 + 149) * 4] ^= 0xff;
+        second.damage = Some(vec![
+            DamageRect { x: 10, y: 10, width: 1, height: 1 },
+            DamageRect { x: 149, y: 99, width: 1, height: 1 },
+        ]);
+        let mut third = second.clone();
+        third.damage = Some(Vec::new());
+        round_trip(vec![first, second, third]);
+    }
+
+    #[test]
+    fn round_trips_planar_frames() {
+        let first = gradient(64, 48, PixelFormat::Yuv420);
+        let mut second = first.clone();
+        second.data[100] ^= 0xff;
+        let last = second.data.len() - 1;
+        second.data[last] ^= 0xff;
+
+        let mut encoder = DeltaEncoder::new();
+        encoder.encode(first.clone());
+        assert!(matches!(encoder.encode(second.clone()), FrameUpdate::Delta { .. }));
+        round_trip(vec![first, second]);
+    }
+
+    #[test]
+    fn layout_change_sends_a_keyframe() {
+        let mut encoder = DeltaEncoder::new();
+        assert!(matches!(encoder.encode(gradient(64, 64, PixelFormat::Rgba)), FrameUpdate::Key { .. }));
+        assert!(matches!(encoder.encode(gradient(64, 64, PixelFormat::Rgba)), FrameUpdate::Tiles { .. }));
+        assert!(matches!(encoder.encode(gradient(32, 64, PixelFormat::Rgba)), FrameUpdate::Key { .. }));
+        encoder.request_keyframe();
+        assert!(matches!(encoder.encode(gradient(32, 64, PixelFormat::Rgba)), FrameUpdate::Key { .. }));
+    }
+
+    #[test]
+    fn gap_needs_a_keyframe() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        let frame = gradient(64, 64, PixelFormat::Rgba);
+
+        assert!(decoder.apply(encoder.encode(frame.clone())).unwrap().is_none());
+        let _lost = encoder.encode(frame.clone());
+        assert_eq!(decoder.apply(encoder.encode(frame.clone())).unwrap_err(), "Expected frame 2, got 3");
+        assert!(decoder.frame().is_none());
+        assert!(decoder.apply(encoder.encode(frame.clone())).is_err());
+
+        encoder.request_keyframe();
+        assert!(decoder.apply(encoder.encode(frame.clone())).unwrap().is_none());
+        assert_eq!(decoder.apply(encoder.encode(frame.clone())).unwrap(), Some(Vec::new()));
+        assert_eq!(decoder.frame().unwrap().data, frame.data);
+    }
+
+    #[test]
+    fn update_before_keyframe_fails() {
+        let mut decoder = DeltaDecoder::new();
+        assert!(decoder.apply(FrameUpdate::Tiles { sequence: 1, tiles: Vec::new() }).is_err());
+        assert!(decoder.apply(FrameUpdate::Delta { sequence: 1, xor: Vec::new() }).is_err());
+    }
+
+    #[test]
+    fn patches_tiles_in_bounds() {
+        let mut frame = Frame::with_stride(vec![0; 48 * 8], 10, 8, PixelFormat::Rgba, 48);
+        let patched = patch_tiles(&mut frame, &[tile(8, 6, 2, 2, 4)]).unwrap();
+        assert_eq!(patched, [DamageRect { x: 8, y: 6, width: 2, height: 2 }]);
+        assert_eq!(frame.data[6 * 48 + 32..6 * 48 + 40], [7; 8]);
+        // The row padding stays untouched
+        assert_eq!(frame.data[6 * 48 + 40..7 * 48], [0; 8]);
+    }
+
+    #[test]
+    fn rejects_tiles_outside_the_frame() {
+        let mut frame = gradient(10, 8, PixelFormat::Rgba);
+        let untouched = frame.data.clone();
+        assert!(patch_tiles(&mut frame, &[tile(9, 0, 2, 1, 4)]).is_err());
+        assert!(patch_tiles(&mut frame, &[tile(0, 7, 1, 2, 4)]).is_err());
+        assert!(patch_tiles(&mut frame, &[tile(u32::MAX, 0, 2, 1, 4)]).is_err());
+        assert!(patch_tiles(&mut frame, &[tile(0, u32::MAX, 1, 2, 4)]).is_err());
+
+        let mut short = tile(0, 0, 2, 2, 4);
+        short.data.pop();
+        assert!(patch_tiles(&mut frame, &[short]).is_err());
+        assert_eq!(frame.data, untouched);
+
+        let mut planar = gradient(16, 16, PixelFormat::Nv12);
+        assert!(patch_tiles(&mut planar, &[tile(0, 0, 1, 1, 1)]).is_err());
+    }
+
+    #[test]
+    fn bad_tiles_drop_the_framebuffer() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        decoder.apply(encoder.encode(gradient(10, 8, PixelFormat::Rgba))).unwrap();
+        assert!(decoder.apply(FrameUpdate::Tiles { sequence: 2, tiles: vec![tile(9, 0, 2, 1, 4)] }).is_err());
+        assert!(decoder.frame().is_none());
+    }
+}
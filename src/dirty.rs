@@ -0,0 +1,211 @@
+use rayon::prelude::*;
+use crate::convert::PixelFormat;
+use crate::screen::{DamageRect, Frame};
+
+// Side of the square tiles frames are compared by, in pixels
+pub const TILE_SIZE: u32 = 64;
+
+// Finds what changed between consecutive frames by hashing them tile by tile
+pub struct DirtyTracker {
+    layout: Option<(u32, u32, PixelFormat)>, // Size and format the hashes belong to
+    hashes: Vec<u64>,
+}
+
+impl DirtyTracker {
+    pub fn new() -> Self {
+        Self { layout: None, hashes: Vec::new() }
+    }
+
+    // Rectangles that differ from the previous frame, empty if nothing changed. The whole
+    // frame is reported after a size or format change. Planar frames are hashed as one block.
+    pub fn update(&mut self, frame: &Frame) -> Vec<DamageRect> {
+        let full = vec![DamageRect { x: 0, y: 0, width: frame.width, height: frame.height }];
+        if frame.width == 0 || frame.height == 0 {
+            self.reset();
+            return Vec::new();
+        }
+        let tiled = frame.format.bytes_per_pixel().filter(|&bpp| {
+            frame.stride >= frame.width as usize * bpp && frame.data.len() >= frame.stride * frame.height as usize
+        });
+        let hashes = match tiled {
+            Some(bpp) => tile_hashes(frame, bpp),
+            None => vec![hash(&frame.data, 0)],
+        };
+        let previous = std::mem::replace(&mut self.hashes, hashes);
+        let layout = Some((frame.width, frame.height, frame.format));
+        if self.layout != layout || previous.len() != self.hashes.len() {
+            self.layout = layout;
+            return full;
+        }
+
+        let changed: Vec<bool> = previous.iter().zip(&self.hashes).map(|(old, new)| old != new).collect();
+        match tiled {
+            Some(_) => merge_tiles(&changed, frame.width.div_ceil(TILE_SIZE) as usize, frame.width, frame.height),
+            None if changed[0] => full,
+            None => Vec::new(),
+        }
+    }
+
+    // Forget the previous frame, so the next one is reported as entirely changed
+    pub fn reset(&mut self) {
+        self.layout = None;
+        self.hashes.clear();
+    }
+}
+
+// One hash per tile, row-major, bands of tiles hashed in parallel
+fn tile_hashes(frame: &Frame, bpp: usize) -> Vec<u64> {
+    let tile = TILE_SIZE as usize;
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let tiles_x = width.div_ceil(tile);
+    let mut hashes = vec![0u64; tiles_x * height.div_ceil(tile)];
+    hashes.par_chunks_mut(tiles_x).enumerate().for_each(|(band, band_hashes)| {
+        for y in band * tile..((band + 1) * tile).min(height) {
+            let row = &frame.data[y * frame.stride..y * frame.stride + width * bpp];
+            for (tile_hash, segment) in band_hashes.iter_mut().zip(row.chunks(tile * bpp)) {
+                *tile_hash = hash(segment, *tile_hash);
+            }
+        }
+    });
+    hashes
+}
+
+// FxHash-style mixing over 8-byte words; only used to notice changes, not for security
+fn hash(data: &[u8], seed: u64) -> u64 {
+    const K: u64 = 0x517c_c1b7_2722_0a95;
+    let mut hash = seed;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        let word = u64::from_le_bytes(word.try_into().unwrap());
+        hash = (hash.rotate_left(5) ^ word).wrapping_mul(K);
+    }
+    for &byte in words.remainder() {
+        hash = (hash.rotate_left(5) ^ byte as u64).wrapping_mul(K);
+    }
+    hash
+}
+
+// Turn changed tiles into rectangles: runs along each tile row, then runs spanning the same
+// columns on consecutive rows are joined
+fn merge_tiles(changed: &[bool], tiles_x: usize, width: u32, height: u32) -> Vec<DamageRect> {
+    let mut rects: Vec<DamageRect> = Vec::new();
+    let mut open = Vec::new(); // Indices in `rects` of the runs found on the previous tile row
+    for (ty, row) in changed.chunks(tiles_x).enumerate() {
+        let y = ty as u32 * TILE_SIZE;
+        let tile_height = TILE_SIZE.min(height - y);
+        let mut current = Vec::new();
+        let mut tx = 0;
+        while tx < row.len() {
+            if !row[tx] {
+                tx += 1;
+                continue;
+            }
+            let start = tx;
+            while tx < row.len() && row[tx] {
+                tx += 1;
+            }
+            let x = start as u32 * TILE_SIZE;
+            let run_width = (tx as u32 * TILE_SIZE).min(width) - x;
+
+            let above = open.iter().copied().find(|&index: &usize| rects[index].x == x && rects[index].width == run_width);
+            match above {
+                Some(index) => {
+                    rects[index].height += tile_height;
+                    current.push(index);
+                }
+                None => {
+                    rects.push(DamageRect { x, y, width: run_width, height: tile_height });
+                    current.push(rects.len() - 1);
+                }
+            }
+        }
+        open = current;
+    }
+    rects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32) -> Frame {
+        Frame::new(vec![0; (width * height * 4) as usize], width, height, PixelFormat::Rgba)
+    }
+
+    fn touch(frame: &mut Frame, x: u32, y: u32) {
+        let index = y as usize * frame.stride + x as usize * 4;
+        frame.data[index] ^= 0xff;
+    }
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> DamageRect {
+        DamageRect { x, y, width, height }
+    }
+
+    #[test]
+    fn first_frame_is_entirely_changed() {
+        let mut tracker = DirtyTracker::new();
+        assert_eq!(tracker.update(&frame(100, 70)), [rect(0, 0, 100, 70)]);
+        assert!(tracker.update(&frame(100, 70)).is_empty());
+    }
+
+    #[test]
+    fn reports_changed_tiles() {
+        let mut tracker = DirtyTracker::new();
+        let mut current = frame(200, 150);
+        tracker.update(&current);
+
+        touch(&mut current, 70, 10);
+        assert_eq!(tracker.update(&current), [rect(64, 0, 64, 64)]);
+
+        // Tiles along the right and bottom edges are clipped to the frame
+        touch(&mut current, 199, 149);
+        assert_eq!(tracker.update(&current), [rect(192, 128, 8, 22)]);
+    }
+
+    #[test]
+    fn size_and_format_changes_report_everything() {
+        let mut tracker = DirtyTracker::new();
+        tracker.update(&frame(64, 64));
+        assert_eq!(tracker.update(&frame(128, 64)), [rect(0, 0, 128, 64)]);
+
+        let mut bgra = frame(128, 64);
+        bgra.format = PixelFormat::Bgra;
+        assert_eq!(tracker.update(&bgra), [rect(0, 0, 128, 64)]);
+
+        tracker.reset();
+        assert_eq!(tracker.update(&bgra), [rect(0, 0, 128, 64)]);
+    }
+
+    #[test]
+    fn ignores_row_padding() {
+        let mut tracker = DirtyTracker::new();
+        let mut padded = Frame::with_stride(vec![0; 80 * 10], 16, 10, PixelFormat::Bgra, 80);
+        tracker.update(&padded);
+        padded.data[70] = 1;
+        assert!(tracker.update(&padded).is_empty());
+    }
+
+    #[test]
+    fn planar_frames_are_hashed_whole() {
+        let mut tracker = DirtyTracker::new();
+        let mut yuv = Frame::new(vec![16; PixelFormat::Yuv420.buffer_len(100, 100)], 100, 100, PixelFormat::Yuv420);
+        tracker.update(&yuv);
+        assert!(tracker.update(&yuv).is_empty());
+        let last = yuv.data.len() - 1;
+        yuv.data[last] = 17;
+        assert_eq!(tracker.update(&yuv), [rect(0, 0, 100, 100)]);
+    }
+
+    #[test]
+    fn merges_runs_and_rows() {
+        // 4x3 tiles over a 250x150 frame:
+        // X X . X
+        // X X . .
+        // . . . X
+        let changed = [true, true, false, true, true, true, false, false, false, false, false, true];
+        assert_eq!(
+            merge_tiles(&changed, 4, 250, 150),
+            [rect(0, 0, 128, 128), rect(192, 0, 58, 64), rect(192, 128, 58, 22)]
+        );
+    }
+}
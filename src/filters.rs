@@ -0,0 +1,1297 @@
+use eframe::egui;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use image::RgbaImage;
+use image::imageops::{self, FilterType};
+use crate::screen::{Frame, CropValues, CropMode, Rotation, Resampling, MaskMode, MaskRegion, BlankKind, BlankSettings, Corner, crop, blank, rotate, scale, mask, banner, draw_polyline, blend_image, corner_label, corner_position};
+use crate::text::{utc_clock, format_elapsed};
+use crate::convert::PixelFormat;
+
+pub const BLANK_FILTER: &str = "Blank";
+
+// One image operation of the caster's filter chain
+pub trait FrameFilter: Send {
+    fn name(&self) -> &str;
+
+    fn apply(&mut self, frame: &mut Frame);
+
+    // Pixel formats `apply` works on; other frames are converted to the first one before it runs.
+    // Filters that currently leave frames untouched take any format so nothing gets converted.
+    fn accepts(&self) -> &'static [PixelFormat] {
+        &[PixelFormat::Rgba]
+    }
+
+    // Whether the output changes over time on its own, e.g. a clock. The pipeline then runs the
+    // chain again on the last source frame while the source has nothing new.
+    fn animated(&self) -> bool {
+        false
+    }
+
+    // Whether the frame is blacked out when this filter cannot run, rather than sent without it
+    fn fails_closed(&self) -> bool {
+        false
+    }
+
+    // Whether the stage stays first in the chain, working on the source frame before any filter
+    // moves pixels. Positions it stores then keep pointing at the same source pixels.
+    fn pinned_first(&self) -> bool {
+        false
+    }
+
+    // Draw the filter settings in the caster UI, returns true if something changed
+    fn ui(&mut self, _ui: &mut egui::Ui) -> bool {
+        false
+    }
+
+    // Pointer dragged on the caster preview. Returns true if the filter takes the
+    // drag; the following Move and End events are then sent to it only.
+    fn preview_drag(&mut self, _drag: PreviewDrag) -> bool {
+        false
+    }
+
+    // Remove whatever was drawn on the preview (Ctrl+E), returns true if something was removed
+    fn clear_drawings(&mut self) -> bool {
+        false
+    }
+
+    // Draw hints over the preview image, e.g. the outline of masked regions. `to_screen` turns
+    // fractions of the frame this filter receives into positions on the preview.
+    fn paint_preview(&self, _painter: &egui::Painter, _to_screen: &dyn Fn(egui::Pos2) -> egui::Pos2) {}
+
+    // Where a point of the frame this filter receives lands in the frame it returns, both in
+    // fractions of the frame size. Only filters that move pixels around change it.
+    fn map_forward(&self, point: egui::Pos2) -> egui::Pos2 {
+        point
+    }
+
+    // Inverse of map_forward
+    fn map_back(&self, point: egui::Pos2) -> egui::Pos2 {
+        point
+    }
+
+    // Persist the filter settings between sessions
+    fn save(&self, _storage: &mut dyn eframe::Storage) {}
+
+    fn load(&mut self, _storage: &dyn eframe::Storage) {}
+
+    // Checked by the pipeline before a new chain replaces the running one
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    // The UI keeps its own copy of the chain and sends clones to the pipeline
+    fn box_clone(&self) -> Box<dyn FrameFilter>;
+}
+
+// Drag on the caster preview, positions in fractions (0.0-1.0) of the frame size
+#[derive(Clone, Copy)]
+pub enum PreviewDrag {
+    Start(egui::Pos2),
+    Move(egui::Pos2),
+    End(egui::Pos2),
+}
+
+// A filter in the chain together with its on/off switch
+pub struct FilterStage {
+    pub enabled: bool,
+    pub filter: Box<dyn FrameFilter>,
+}
+
+impl Clone for FilterStage {
+    fn clone(&self) -> Self {
+        Self { enabled: self.enabled, filter: self.filter.box_clone() }
+    }
+}
+
+impl FilterStage {
+    pub fn new(filter: Box<dyn FrameFilter>, enabled: bool) -> Self {
+        Self { enabled, filter }
+    }
+}
+
+// Chain used by a fresh caster: mask, crop, scale, rotate (off), overlay, annotations, blank (off)
+pub fn default_chain() -> Vec<FilterStage> {
+    vec![
+        FilterStage::new(Box::new(MaskFilter::new()), true),
+        FilterStage::new(Box::new(CropFilter::new()), true),
+        FilterStage::new(Box::new(ScaleFilter::new()), true),
+        FilterStage::new(Box::new(RotateFilter::new()), false),
+        FilterStage::new(Box::new(OverlayFilter::new()), true),
+        FilterStage::new(Box::new(AnnotationFilter::new()), true),
+        FilterStage::new(Box::new(BlankFilter::new()), false),
+    ]
+}
+
+// Run every enabled filter in order, converting the frame only when a filter needs it
+pub fn apply_chain(chain: &mut [FilterStage], frame: &mut Frame) {
+    for stage in chain.iter_mut().filter(|stage| stage.enabled) {
+        let accepts = stage.filter.accepts();
+        // Filters taking every format leave the pixels alone, padded rows included
+        let any_format = accepts.len() == PixelFormat::ALL.len();
+        if !accepts.contains(&frame.format) || !(any_format || frame.is_packed()) {
+            if let Err(e) = frame.convert(accepts[0]) {
+                if stage.filter.fails_closed() {
+                    eprintln!("{} cannot run, blacking out the frame: {}", stage.filter.name(), e);
+                    black_out(frame);
+                } else {
+                    eprintln!("Skipping {}: {}", stage.filter.name(), e);
+                }
+                continue;
+            }
+        }
+        stage.filter.apply(frame);
+    }
+}
+
+// Replace the frame with black RGBA pixels of the same size
+fn black_out(frame: &mut Frame) {
+    let pixels = frame.width as usize * frame.height as usize;
+    *frame = Frame::new([0, 0, 0, 255].repeat(pixels), frame.width, frame.height, PixelFormat::Rgba);
+}
+
+#[derive(Clone)]
+pub struct CropFilter {
+    pub values: CropValues,
+}
+
+impl CropFilter {
+    pub fn new() -> Self {
+        Self { values: CropValues::new(0.0, 0.0, 0.0, 0.0) }
+    }
+
+    // Top-left corner and size of the part kept by trimming, in fractions of the input frame
+    fn kept_area(&self) -> (egui::Pos2, egui::Vec2) {
+        let values = &self.values;
+        let origin = egui::pos2(values.left, values.top) / 100.0;
+        let size = egui::vec2(100.0 - values.left - values.right, 100.0 - values.top - values.bottom) / 100.0;
+        (origin, size.max(egui::vec2(f32::EPSILON, f32::EPSILON)))
+    }
+}
+
+impl FrameFilter for CropFilter {
+    fn name(&self) -> &str {
+        "Crop"
+    }
+
+    fn apply(&mut self, frame: &mut Frame) {
+        crop(frame, &self.values);
+    }
+
+    fn map_forward(&self, point: egui::Pos2) -> egui::Pos2 {
+        match self.values.mode {
+            CropMode::Trim => {
+                let (origin, size) = self.kept_area();
+                ((point - origin) / size).to_pos2()
+            }
+            CropMode::Fill(_) => point,
+        }
+    }
+
+    fn map_back(&self, point: egui::Pos2) -> egui::Pos2 {
+        match self.values.mode {
+            CropMode::Trim => {
+                let (origin, size) = self.kept_area();
+                origin + point.to_vec2() * size
+            }
+            CropMode::Fill(_) => point,
+        }
+    }
+
+    fn accepts(&self) -> &'static [PixelFormat] {
+        let values = &self.values;
+        if [values.left, values.right, values.top, values.bottom].iter().all(|&value| value == 0.0) {
+            &PixelFormat::ALL
+        } else {
+            &[PixelFormat::Rgba]
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let previous = self.values.clone();
+        let values = &mut self.values;
+        ui.columns(4, |columns| {
+            let slider_width = columns[0].available_width(); // Width of each slider (columns width)
+
+            // Left Crop Slider
+            columns[0].vertical(|ui| {
+                ui.label("Left");
+                ui.add_sized(
+                    [slider_width, 20.0],
+                    egui::Slider::new(&mut values.left, 0.0..=100.0 - values.right),
+                );
+            });
+
+            // Right Crop Slider
+            columns[1].vertical(|ui| {
+                ui.label("Right");
+                ui.add_sized(
+                    [slider_width, 20.0],
+                    egui::Slider::new(&mut values.right, 0.0..=100.0 - values.left),
+                );
+            });
+
+            // Top Crop Slider
+            columns[2].vertical(|ui| {
+                ui.label("Top");
+                ui.add_sized(
+                    [slider_width, 20.0],
+                    egui::Slider::new(&mut values.top, 0.0..=100.0 - values.bottom),
+                );
+            });
+
+            // Bottom Crop Slider
+            columns[3].vertical(|ui| {
+                ui.label("Bottom");
+                ui.add_sized(
+                    [slider_width, 20.0],
+                    egui::Slider::new(&mut values.bottom, 0.0..=100.0 - values.top),
+                );
+            });
+        });
+
+        // Trim the margins or paint them with a colour
+        ui.horizontal(|ui| {
+            let mut fill_color = match values.mode {
+                CropMode::Fill(color) => color,
+                CropMode::Trim => [255, 255, 255, 255],
+            };
+            if ui.radio(values.mode == CropMode::Trim, "Trim").clicked() {
+                values.mode = CropMode::Trim;
+            }
+            if ui.radio(values.mode != CropMode::Trim, "Fill").clicked() {
+                values.mode = CropMode::Fill(fill_color);
+            }
+            if let CropMode::Fill(_) = values.mode {
+                if ui.color_edit_button_srgba_unmultiplied(&mut fill_color).changed() {
+                    values.mode = CropMode::Fill(fill_color);
+                }
+            }
+        });
+        *values != previous
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.values.validate()
+    }
+
+    fn box_clone(&self) -> Box<dyn FrameFilter> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct BlankFilter {
+    pub settings: BlankSettings,
+    slate: Option<Arc<RgbaImage>>,  // Decoded slate image
+    fitted: Option<Arc<RgbaImage>>, // Slate resized for the current frame size
+    slate_error: Option<String>,
+}
+
+impl BlankFilter {
+    pub fn new() -> Self {
+        Self { settings: BlankSettings::new(), slate: None, fitted: None, slate_error: None }
+    }
+
+    fn load_slate(&mut self) {
+        self.fitted = None;
+        match image::open(&self.settings.slate_path) {
+            Ok(image) => {
+                self.slate = Some(Arc::new(image.to_rgba8()));
+                self.slate_error = None;
+            }
+            Err(e) => {
+                self.slate = None;
+                self.slate_error = Some(format!("Cannot load slate: {}", e));
+            }
+        }
+    }
+
+    // Slate scaled to fit the frame, recomputed only when the frame size changes
+    fn fitted_slate(&mut self, width: u32, height: u32) -> Option<Arc<RgbaImage>> {
+        let slate = self.slate.as_ref()?;
+        let factor = f64::min(width as f64 / slate.width() as f64, height as f64 / slate.height() as f64);
+        let fitted_width = ((slate.width() as f64 * factor) as u32).clamp(1, width);
+        let fitted_height = ((slate.height() as f64 * factor) as u32).clamp(1, height);
+        let up_to_date = self.fitted.as_ref().is_some_and(|fitted| fitted.dimensions() == (fitted_width, fitted_height));
+        if !up_to_date {
+            let resized = imageops::resize(slate.as_ref(), fitted_width, fitted_height, FilterType::Triangle);
+            self.fitted = Some(Arc::new(resized));
+        }
+        self.fitted.clone()
+    }
+}
+
+impl FrameFilter for BlankFilter {
+    fn name(&self) -> &str {
+        BLANK_FILTER
+    }
+
+    fn apply(&mut self, frame: &mut Frame) {
+        let slate = match self.settings.kind {
+            BlankKind::Slate => self.fitted_slate(frame.width, frame.height),
+            _ => None,
+        };
+        blank(frame, &self.settings, slate.as_deref());
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let previous = self.settings.clone();
+        let mut reload = false;
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.settings.kind, BlankKind::Color, "Colour");
+            ui.radio_value(&mut self.settings.kind, BlankKind::Slate, "Slate");
+            ui.radio_value(&mut self.settings.kind, BlankKind::Message, "Message");
+            ui.color_edit_button_srgba_unmultiplied(&mut self.settings.color);
+        });
+        match self.settings.kind {
+            BlankKind::Color => {}
+            BlankKind::Slate => {
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut self.settings.slate_path).hint_text("Slate image (PNG/JPEG)"));
+                    reload = ui.button("Load").clicked();
+                });
+                if let Some(error) = &self.slate_error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+            }
+            BlankKind::Message => {
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::multiline(&mut self.settings.message).desired_rows(2));
+                    ui.color_edit_button_srgba_unmultiplied(&mut self.settings.text_color);
+                });
+            }
+        }
+        if reload {
+            self.load_slate();
+        }
+        reload || self.settings != previous
+    }
+
+    fn save(&self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, "blank_settings", &self.settings);
+    }
+
+    fn load(&mut self, storage: &dyn eframe::Storage) {
+        if let Some(settings) = eframe::get_value::<BlankSettings>(storage, "blank_settings") {
+            self.settings = settings;
+            if !self.settings.slate_path.is_empty() {
+                self.load_slate();
+            }
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn FrameFilter> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct RotateFilter {
+    pub rotation: Rotation,
+}
+
+impl RotateFilter {
+    pub fn new() -> Self {
+        Self { rotation: Rotation::Deg90 }
+    }
+}
+
+impl FrameFilter for RotateFilter {
+    fn name(&self) -> &str {
+        "Rotate"
+    }
+
+    fn apply(&mut self, frame: &mut Frame) {
+        rotate(frame, self.rotation);
+    }
+
+    fn map_forward(&self, point: egui::Pos2) -> egui::Pos2 {
+        match self.rotation {
+            Rotation::Deg90 => egui::pos2(1.0 - point.y, point.x),
+            Rotation::Deg180 => egui::pos2(1.0 - point.x, 1.0 - point.y),
+            Rotation::Deg270 => egui::pos2(point.y, 1.0 - point.x),
+        }
+    }
+
+    fn map_back(&self, point: egui::Pos2) -> egui::Pos2 {
+        match self.rotation {
+            Rotation::Deg90 => egui::pos2(point.y, 1.0 - point.x),
+            Rotation::Deg180 => egui::pos2(1.0 - point.x, 1.0 - point.y),
+            Rotation::Deg270 => egui::pos2(1.0 - point.y, point.x),
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let previous = self.rotation;
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.rotation, Rotation::Deg90, "90°");
+            ui.radio_value(&mut self.rotation, Rotation::Deg180, "180°");
+            ui.radio_value(&mut self.rotation, Rotation::Deg270, "270°");
+        });
+        self.rotation != previous
+    }
+
+    fn box_clone(&self) -> Box<dyn FrameFilter> {
+        Box::new(self.clone())
+    }
+}
+
+// Output resolution presets, each a bounding box the frame is fitted into
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScalePreset {
+    Native,
+    P1080,
+    P720,
+    P480,
+    Custom,
+}
+
+impl ScalePreset {
+    fn label(&self) -> &'static str {
+        match self {
+            ScalePreset::Native => "Native",
+            ScalePreset::P1080 => "1080p",
+            ScalePreset::P720 => "720p",
+            ScalePreset::P480 => "480p",
+            ScalePreset::Custom => "Custom",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ScaleFilter {
+    pub preset: ScalePreset,
+    pub custom_size: [u32; 2], // Bounding box used by the Custom preset
+    pub resampling: Resampling,
+}
+
+impl ScaleFilter {
+    pub fn new() -> Self {
+        Self {
+            preset: ScalePreset::Native,
+            custom_size: [1600, 900],
+            resampling: Resampling::Bilinear,
+        }
+    }
+
+    fn bounds(&self) -> Option<(u32, u32)> {
+        match self.preset {
+            ScalePreset::Native => None,
+            ScalePreset::P1080 => Some((1920, 1080)),
+            ScalePreset::P720 => Some((1280, 720)),
+            ScalePreset::P480 => Some((854, 480)),
+            ScalePreset::Custom => Some((self.custom_size[0], self.custom_size[1])),
+        }
+    }
+}
+
+impl FrameFilter for ScaleFilter {
+    fn name(&self) -> &str {
+        "Scale"
+    }
+
+    fn apply(&mut self, frame: &mut Frame) {
+        if let Some((max_width, max_height)) = self.bounds() {
+            // Standard presets turn their box around for portrait frames
+            let portrait = frame.height > frame.width && self.preset != ScalePreset::Custom;
+            let (max_width, max_height) = if portrait {
+                (max_height, max_width)
+            } else {
+                (max_width, max_height)
+            };
+            scale(frame, max_width, max_height, self.resampling);
+        }
+    }
+
+    fn accepts(&self) -> &'static [PixelFormat] {
+        if self.bounds().is_none() {
+            &PixelFormat::ALL
+        } else {
+            &[PixelFormat::Rgba]
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let previous = (self.preset, self.custom_size, self.resampling);
+        ui.horizontal(|ui| {
+            for preset in [ScalePreset::Native, ScalePreset::P1080, ScalePreset::P720, ScalePreset::P480, ScalePreset::Custom] {
+                ui.radio_value(&mut self.preset, preset, preset.label());
+            }
+            if self.preset == ScalePreset::Custom {
+                ui.add(egui::DragValue::new(&mut self.custom_size[0]).range(16..=7680).suffix(" px"));
+                ui.label("x");
+                ui.add(egui::DragValue::new(&mut self.custom_size[1]).range(16..=4320).suffix(" px"));
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Resampling");
+            ui.radio_value(&mut self.resampling, Resampling::Nearest, "Nearest");
+            ui.radio_value(&mut self.resampling, Resampling::Bilinear, "Bilinear");
+            ui.radio_value(&mut self.resampling, Resampling::Lanczos, "Lanczos");
+        });
+        (self.preset, self.custom_size, self.resampling) != previous
+    }
+
+    fn box_clone(&self) -> Box<dyn FrameFilter> {
+        Box::new(self.clone())
+    }
+}
+
+// Privacy masks: rectangles hidden by a solid fill, a blur or pixelation
+#[derive(Clone)]
+pub struct MaskFilter {
+    pub regions: Vec<MaskRegion>,
+    pub new_mode: MaskMode, // Mode given to regions dragged on the preview
+    selection: Option<(egui::Pos2, egui::Pos2)>, // Region being dragged
+}
+
+impl MaskFilter {
+    pub fn new() -> Self {
+        Self { regions: Vec::new(), new_mode: MaskMode::Blur(12.0), selection: None }
+    }
+}
+
+fn mask_mode_ui(ui: &mut egui::Ui, mode: &mut MaskMode) {
+    let mut color = [0, 0, 0, 255];
+    let mut sigma = 12.0;
+    let mut block = 16;
+    match *mode {
+        MaskMode::Solid(value) => color = value,
+        MaskMode::Blur(value) => sigma = value,
+        MaskMode::Pixelate(value) => block = value,
+    }
+    if ui.radio(matches!(mode, MaskMode::Solid(_)), "Solid").clicked() {
+        *mode = MaskMode::Solid(color);
+    }
+    if ui.radio(matches!(mode, MaskMode::Blur(_)), "Blur").clicked() {
+        *mode = MaskMode::Blur(sigma);
+    }
+    if ui.radio(matches!(mode, MaskMode::Pixelate(_)), "Pixelate").clicked() {
+        *mode = MaskMode::Pixelate(block);
+    }
+    match mode {
+        MaskMode::Solid(color) => {
+            ui.color_edit_button_srgba_unmultiplied(color);
+        }
+        MaskMode::Blur(sigma) => {
+            ui.add(egui::DragValue::new(sigma).range(1.0..=50.0).prefix("sigma "));
+        }
+        MaskMode::Pixelate(block) => {
+            ui.add(egui::DragValue::new(block).range(2..=128).suffix(" px"));
+        }
+    }
+}
+
+impl FrameFilter for MaskFilter {
+    fn name(&self) -> &str {
+        "Mask"
+    }
+
+    fn apply(&mut self, frame: &mut Frame) {
+        for region in &self.regions {
+            if let Err(e) = mask(frame, region) {
+                eprintln!("Mask cannot run, blacking out the frame: {}", e);
+                black_out(frame);
+                return;
+            }
+        }
+    }
+
+    // Never stream what a region is meant to hide
+    fn fails_closed(&self) -> bool {
+        !self.regions.is_empty()
+    }
+
+    // Regions cover source pixels, whatever the crop or rotation does afterwards
+    fn pinned_first(&self) -> bool {
+        true
+    }
+
+    fn accepts(&self) -> &'static [PixelFormat] {
+        if self.regions.is_empty() {
+            &PixelFormat::ALL
+        } else {
+            &[PixelFormat::Rgba]
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let previous = self.regions.clone();
+        ui.horizontal(|ui| {
+            ui.label("Drag on the preview to add:");
+            mask_mode_ui(ui, &mut self.new_mode);
+        });
+        let mut removed = None;
+        for (index, region) in self.regions.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(format!("Region {}", index + 1));
+                mask_mode_ui(ui, &mut region.mode);
+                if ui.button("Remove").clicked() {
+                    removed = Some(index);
+                }
+            });
+        }
+        if let Some(index) = removed {
+            self.regions.remove(index);
+        }
+        self.regions != previous
+    }
+
+    fn preview_drag(&mut self, drag: PreviewDrag) -> bool {
+        match drag {
+            PreviewDrag::Start(pos) => self.selection = Some((pos, pos)),
+            PreviewDrag::Move(pos) => {
+                if let Some((_, end)) = &mut self.selection {
+                    *end = pos;
+                }
+            }
+            PreviewDrag::End(pos) => {
+                if let Some((start, _)) = self.selection.take() {
+                    let selection = egui::Rect::from_two_pos(start, pos);
+                    if selection.width() > 0.0 && selection.height() > 0.0 {
+                        self.regions.push(MaskRegion {
+                            x: selection.min.x,
+                            y: selection.min.y,
+                            width: selection.width(),
+                            height: selection.height(),
+                            mode: self.new_mode,
+                        });
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn paint_preview(&self, painter: &egui::Painter, to_screen: &dyn Fn(egui::Pos2) -> egui::Pos2) {
+        // Later filters only turn the frame by right angles, so opposite corners stay opposite
+        for region in &self.regions {
+            let rect = egui::Rect::from_two_pos(
+                to_screen(egui::pos2(region.x, region.y)),
+                to_screen(egui::pos2(region.x + region.width, region.y + region.height)),
+            );
+            painter.rect_stroke(rect, 0.0, egui::Stroke::new(1.0, egui::Color32::YELLOW));
+        }
+        if let Some((start, end)) = self.selection {
+            let rect = egui::Rect::from_two_pos(to_screen(start), to_screen(end));
+            painter.rect_stroke(rect, 0.0, egui::Stroke::new(2.0, egui::Color32::RED));
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn FrameFilter> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ClockMode {
+    Off,
+    WallClock, // Current UTC time
+    Elapsed,   // Time since the overlay was enabled or reset
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct OverlaySettings {
+    pub banner_text: String, // Empty hides the banner
+    pub banner_at_top: bool,
+    pub logo_path: String,
+    pub logo_corner: Corner,
+    pub logo_opacity: f32,
+    pub logo_height: f32, // Percentage of the frame height
+    pub clock: ClockMode,
+    pub clock_corner: Corner,
+}
+
+fn corner_ui(ui: &mut egui::Ui, id: &str, corner: &mut Corner) {
+    egui::ComboBox::from_id_source(id)
+        .selected_text(match corner {
+            Corner::TopLeft => "Top left",
+            Corner::TopRight => "Top right",
+            Corner::BottomLeft => "Bottom left",
+            Corner::BottomRight => "Bottom right",
+        })
+        .show_ui(ui, |ui| {
+            ui.selectable_value(corner, Corner::TopLeft, "Top left");
+            ui.selectable_value(corner, Corner::TopRight, "Top right");
+            ui.selectable_value(corner, Corner::BottomLeft, "Bottom left");
+            ui.selectable_value(corner, Corner::BottomRight, "Bottom right");
+        });
+}
+
+// Branding and timing burned into the stream: banner, logo and clock
+#[derive(Clone)]
+pub struct OverlayFilter {
+    pub settings: OverlaySettings,
+    started: Instant,
+    logo: Option<Arc<RgbaImage>>,
+    fitted: Option<Arc<RgbaImage>>, // Logo resized for the current frame height
+    logo_error: Option<String>,
+}
+
+impl OverlayFilter {
+    pub fn new() -> Self {
+        Self {
+            settings: OverlaySettings {
+                banner_text: String::new(),
+                banner_at_top: false,
+                logo_path: String::new(),
+                logo_corner: Corner::TopRight,
+                logo_opacity: 0.8,
+                logo_height: 10.0,
+                clock: ClockMode::Off,
+                clock_corner: Corner::BottomRight,
+            },
+            started: Instant::now(),
+            logo: None,
+            fitted: None,
+            logo_error: None,
+        }
+    }
+
+    fn load_logo(&mut self) {
+        self.fitted = None;
+        if self.settings.logo_path.is_empty() {
+            self.logo = None;
+            self.logo_error = None;
+            return;
+        }
+        match image::open(&self.settings.logo_path) {
+            Ok(image) => {
+                self.logo = Some(Arc::new(image.to_rgba8()));
+                self.logo_error = None;
+            }
+            Err(e) => {
+                self.logo = None;
+                self.logo_error = Some(format!("Cannot load logo: {}", e));
+            }
+        }
+    }
+
+    fn fitted_logo(&mut self, frame_height: u32) -> Option<Arc<RgbaImage>> {
+        let logo = self.logo.as_ref()?;
+        let height = ((frame_height as f32 * self.settings.logo_height / 100.0) as u32).max(1);
+        let width = ((logo.width() as f32 * height as f32 / logo.height() as f32) as u32).max(1);
+        let up_to_date = self.fitted.as_ref().is_some_and(|fitted| fitted.dimensions() == (width, height));
+        if !up_to_date {
+            self.fitted = Some(Arc::new(imageops::resize(logo.as_ref(), width, height, FilterType::Triangle)));
+        }
+        self.fitted.clone()
+    }
+}
+
+impl FrameFilter for OverlayFilter {
+    fn name(&self) -> &str {
+        "Overlay"
+    }
+
+    fn apply(&mut self, frame: &mut Frame) {
+        if !self.settings.banner_text.is_empty() {
+            banner(frame, &self.settings.banner_text, self.settings.banner_at_top, [0, 0, 0, 170], [255, 255, 255, 255]);
+        }
+        if let Some(logo) = self.fitted_logo(frame.height) {
+            let margin = frame.height / 40;
+            let (x, y) = corner_position(frame, self.settings.logo_corner, logo.width(), logo.height(), margin);
+            blend_image(frame, &logo, x, y, self.settings.logo_opacity);
+        }
+        let clock = match self.settings.clock {
+            ClockMode::Off => None,
+            ClockMode::WallClock => Some(format!("{} UTC", utc_clock())),
+            ClockMode::Elapsed => Some(format_elapsed(self.started.elapsed())),
+        };
+        if let Some(clock) = clock {
+            corner_label(frame, &clock, self.settings.clock_corner, [255, 255, 255, 255]);
+        }
+    }
+
+    fn animated(&self) -> bool {
+        self.settings.clock != ClockMode::Off
+    }
+
+    fn accepts(&self) -> &'static [PixelFormat] {
+        let settings = &self.settings;
+        if settings.banner_text.is_empty() && self.logo.is_none() && settings.clock == ClockMode::Off {
+            &PixelFormat::ALL
+        } else {
+            &[PixelFormat::Rgba]
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let previous = self.settings.clone();
+        let mut reload = false;
+        let mut reset = false;
+        ui.horizontal(|ui| {
+            ui.label("Banner");
+            ui.add(egui::TextEdit::singleline(&mut self.settings.banner_text).hint_text("Session title"));
+            ui.checkbox(&mut self.settings.banner_at_top, "Top");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Logo");
+            ui.add(egui::TextEdit::singleline(&mut self.settings.logo_path).hint_text("PNG file"));
+            reload = ui.button("Load").clicked();
+            corner_ui(ui, "logo_corner", &mut self.settings.logo_corner);
+            ui.add(egui::Slider::new(&mut self.settings.logo_opacity, 0.0..=1.0).text("opacity"));
+            ui.add(egui::DragValue::new(&mut self.settings.logo_height).range(1.0..=50.0).suffix(" %"));
+        });
+        if let Some(error) = &self.logo_error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        ui.horizontal(|ui| {
+            ui.label("Clock");
+            ui.radio_value(&mut self.settings.clock, ClockMode::Off, "Off");
+            ui.radio_value(&mut self.settings.clock, ClockMode::WallClock, "Wall clock");
+            ui.radio_value(&mut self.settings.clock, ClockMode::Elapsed, "Elapsed");
+            corner_ui(ui, "clock_corner", &mut self.settings.clock_corner);
+            reset = ui.button("Reset").clicked();
+        });
+        if reload {
+            self.load_logo();
+        }
+        if reset || (self.settings.clock == ClockMode::Elapsed && previous.clock != ClockMode::Elapsed) {
+            self.started = Instant::now();
+            reset = true;
+        }
+        reload || reset || self.settings != previous
+    }
+
+    fn save(&self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, "overlay_settings", &self.settings);
+    }
+
+    fn load(&mut self, storage: &dyn eframe::Storage) {
+        if let Some(settings) = eframe::get_value::<OverlaySettings>(storage, "overlay_settings") {
+            self.settings = settings;
+            self.load_logo();
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn FrameFilter> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AnnotationTool {
+    Off, // Drags on the preview go to the other filters
+    Pen,
+    Arrow,
+    Rectangle,
+    Highlighter,
+    Eraser,
+}
+
+// One annotation, points in fractions of the frame size
+#[derive(Clone)]
+struct AnnotationStroke {
+    tool: AnnotationTool,
+    points: Vec<egui::Pos2>,
+    color: [u8; 4],
+    thickness: f32, // Percentage of the frame height
+    created: Instant,
+}
+
+// Strokes drawn by the presenter on the preview, composited into the stream
+#[derive(Clone)]
+pub struct AnnotationFilter {
+    pub tool: AnnotationTool,
+    pub color: [u8; 4],
+    pub thickness: f32, // Percentage of the frame height
+    pub fade: bool,
+    pub fade_after: f32, // Seconds before a stroke disappears
+    strokes: Vec<AnnotationStroke>,
+}
+
+impl AnnotationFilter {
+    pub fn new() -> Self {
+        Self {
+            tool: AnnotationTool::Off,
+            color: [255, 0, 0, 255],
+            thickness: 0.5,
+            fade: false,
+            fade_after: 5.0,
+            strokes: Vec::new(),
+        }
+    }
+
+    // Opacity of a stroke, fading out over the last second of its life
+    fn opacity(&self, stroke: &AnnotationStroke) -> f32 {
+        if !self.fade {
+            return 1.0;
+        }
+        let remaining = self.fade_after - stroke.created.elapsed().as_secs_f32();
+        remaining.clamp(0.0, 1.0)
+    }
+
+    fn remove_expired(&mut self) {
+        if self.fade {
+            let lifetime = Duration::from_secs_f32(self.fade_after.max(0.0));
+            self.strokes.retain(|stroke| stroke.created.elapsed() < lifetime);
+        }
+    }
+
+    // Erase every stroke passing near `pos`, anywhere along its lines
+    fn erase_at(&mut self, pos: egui::Pos2) {
+        self.strokes.retain(|stroke| stroke.segments().iter().all(|&(a, b)| segment_distance(pos, a, b) > 0.02));
+    }
+}
+
+impl AnnotationStroke {
+    // Lines the stroke is drawn with; the arrow head is small enough to leave out
+    fn segments(&self) -> Vec<(egui::Pos2, egui::Pos2)> {
+        let (Some(&a), Some(&b)) = (self.points.first(), self.points.last()) else {
+            return Vec::new();
+        };
+        match self.tool {
+            AnnotationTool::Rectangle => {
+                let corners = [a, egui::pos2(b.x, a.y), b, egui::pos2(a.x, b.y), a];
+                corners.windows(2).map(|edge| (edge[0], edge[1])).collect()
+            }
+            AnnotationTool::Arrow => vec![(a, b)],
+            _ if self.points.len() == 1 => vec![(a, a)],
+            _ => self.points.windows(2).map(|line| (line[0], line[1])).collect(),
+        }
+    }
+}
+
+// Distance from `pos` to the closest point of the segment between `a` and `b`
+fn segment_distance(pos: egui::Pos2, a: egui::Pos2, b: egui::Pos2) -> f32 {
+    let line = b - a;
+    let along = if line.length_sq() > 0.0 { ((pos - a).dot(line) / line.length_sq()).clamp(0.0, 1.0) } else { 0.0 };
+    pos.distance(a + line * along)
+}
+
+impl FrameFilter for AnnotationFilter {
+    fn name(&self) -> &str {
+        "Annotations"
+    }
+
+    fn apply(&mut self, frame: &mut Frame) {
+        self.remove_expired();
+        let size = egui::vec2(frame.width as f32, frame.height as f32);
+        for stroke in &self.strokes {
+            let to_pixels = |point: &egui::Pos2| (point.x * size.x, point.y * size.y);
+            let thickness = stroke.thickness / 100.0 * size.y;
+            let mut color = stroke.color;
+            color[3] = (color[3] as f32 * self.opacity(stroke)) as u8;
+
+            match stroke.tool {
+                AnnotationTool::Pen | AnnotationTool::Highlighter => {
+                    let points: Vec<(f32, f32)> = stroke.points.iter().map(to_pixels).collect();
+                    draw_polyline(frame, &points, thickness, color);
+                }
+                AnnotationTool::Rectangle => {
+                    let (Some(a), Some(b)) = (stroke.points.first(), stroke.points.last()) else {
+                        continue;
+                    };
+                    let ((ax, ay), (bx, by)) = (to_pixels(a), to_pixels(b));
+                    draw_polyline(frame, &[(ax, ay), (bx, ay), (bx, by), (ax, by), (ax, ay)], thickness, color);
+                }
+                AnnotationTool::Arrow => {
+                    let (Some(a), Some(b)) = (stroke.points.first(), stroke.points.last()) else {
+                        continue;
+                    };
+                    let ((ax, ay), (bx, by)) = (to_pixels(a), to_pixels(b));
+                    draw_polyline(frame, &[(ax, ay), (bx, by)], thickness, color);
+
+                    // Head: two lines at +/-30 degrees from the shaft, back from the tip
+                    let angle = (ay - by).atan2(ax - bx);
+                    let head = thickness * 5.0;
+                    for side in [-0.5f32, 0.5] {
+                        let tip = (bx + head * (angle + side).cos(), by + head * (angle + side).sin());
+                        draw_polyline(frame, &[(bx, by), tip], thickness, color);
+                    }
+                }
+                AnnotationTool::Off | AnnotationTool::Eraser => {}
+            }
+        }
+    }
+
+    // Fading strokes disappear by themselves
+    fn animated(&self) -> bool {
+        self.fade && !self.strokes.is_empty()
+    }
+
+    fn accepts(&self) -> &'static [PixelFormat] {
+        if self.strokes.is_empty() {
+            &PixelFormat::ALL
+        } else {
+            &[PixelFormat::Rgba]
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let previous = (self.tool, self.color, self.thickness, self.fade, self.fade_after, self.strokes.len());
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.tool, AnnotationTool::Off, "Off");
+            ui.radio_value(&mut self.tool, AnnotationTool::Pen, "Pen");
+            ui.radio_value(&mut self.tool, AnnotationTool::Arrow, "Arrow");
+            ui.radio_value(&mut self.tool, AnnotationTool::Rectangle, "Rectangle");
+            ui.radio_value(&mut self.tool, AnnotationTool::Highlighter, "Highlighter");
+            ui.radio_value(&mut self.tool, AnnotationTool::Eraser, "Eraser");
+        });
+        ui.horizontal(|ui| {
+            ui.color_edit_button_srgba_unmultiplied(&mut self.color);
+            ui.add(egui::DragValue::new(&mut self.thickness).range(0.1..=5.0).speed(0.05).suffix(" %"));
+            ui.checkbox(&mut self.fade, "Fade after");
+            ui.add_enabled(self.fade, egui::DragValue::new(&mut self.fade_after).range(1.0..=60.0).suffix(" s"));
+            if ui.button("Clear (Ctrl + E)").clicked() {
+                self.clear_drawings();
+            }
+        });
+        self.remove_expired();
+        previous != (self.tool, self.color, self.thickness, self.fade, self.fade_after, self.strokes.len())
+    }
+
+    fn clear_drawings(&mut self) -> bool {
+        let had_strokes = !self.strokes.is_empty();
+        self.strokes.clear();
+        had_strokes
+    }
+
+    fn preview_drag(&mut self, drag: PreviewDrag) -> bool {
+        match (self.tool, drag) {
+            (AnnotationTool::Off, _) => return false,
+            (AnnotationTool::Eraser, PreviewDrag::Start(pos) | PreviewDrag::Move(pos) | PreviewDrag::End(pos)) => {
+                self.erase_at(pos);
+            }
+            (tool, PreviewDrag::Start(pos)) => {
+                // The highlighter is a wide translucent pen
+                let (color, thickness) = match tool {
+                    AnnotationTool::Highlighter => ([self.color[0], self.color[1], self.color[2], 90], self.thickness * 4.0),
+                    _ => (self.color, self.thickness),
+                };
+                self.strokes.push(AnnotationStroke { tool, points: vec![pos], color, thickness, created: Instant::now() });
+            }
+            (tool, PreviewDrag::Move(pos) | PreviewDrag::End(pos)) => {
+                if let Some(stroke) = self.strokes.last_mut() {
+                    match tool {
+                        // Shapes only need their two corners
+                        AnnotationTool::Arrow | AnnotationTool::Rectangle => {
+                            stroke.points.truncate(1);
+                            stroke.points.push(pos);
+                        }
+                        _ => stroke.points.push(pos),
+                    }
+                    stroke.created = Instant::now();
+                }
+            }
+        }
+        true
+    }
+
+    fn box_clone(&self) -> Box<dyn FrameFilter> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RGBA frame whose pixels hold their own coordinates: [x, y, 0, 255]
+    fn coordinates(width: u32, height: u32) -> Frame {
+        let data = (0..height).flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, 0, 255])).collect();
+        Frame::new(data, width, height, PixelFormat::Rgba)
+    }
+
+    fn pixel(frame: &Frame, x: u32, y: u32) -> [u8; 4] {
+        let index = y as usize * frame.stride + x as usize * 4;
+        frame.data[index..index + 4].try_into().unwrap()
+    }
+
+    fn crop_filter(left: f32, right: f32, top: f32, bottom: f32, mode: CropMode) -> CropFilter {
+        CropFilter { values: CropValues { mode, ..CropValues::new(left, right, top, bottom) } }
+    }
+
+    #[test]
+    fn crop_trims_margins() {
+        let mut frame = coordinates(100, 50);
+        crop_filter(10.0, 20.0, 10.0, 0.0, CropMode::Trim).apply(&mut frame);
+        assert_eq!((frame.width, frame.height), (70, 45));
+        assert_eq!(frame.data.len(), 70 * 45 * 4);
+        assert_eq!(pixel(&frame, 0, 0), [10, 5, 0, 255]);
+        assert_eq!(pixel(&frame, 69, 44), [79, 49, 0, 255]);
+    }
+
+    #[test]
+    fn crop_fills_margins() {
+        let mut frame = coordinates(100, 50);
+        crop_filter(10.0, 0.0, 0.0, 10.0, CropMode::Fill([1, 2, 3, 255])).apply(&mut frame);
+        assert_eq!((frame.width, frame.height), (100, 50));
+        assert_eq!(pixel(&frame, 9, 0), [1, 2, 3, 255]);
+        assert_eq!(pixel(&frame, 10, 0), [10, 0, 0, 255]);
+        assert_eq!(pixel(&frame, 50, 45), [1, 2, 3, 255]);
+    }
+
+    #[test]
+    fn crop_keeps_one_pixel() {
+        let mut frame = coordinates(10, 10);
+        crop_filter(60.0, 40.0, 0.0, 100.0, CropMode::Trim).apply(&mut frame);
+        assert_eq!((frame.width, frame.height), (1, 1));
+        assert_eq!(frame.data.len(), 4);
+    }
+
+    #[test]
+    fn crop_leaves_empty_frames_alone() {
+        for (width, height) in [(0, 0), (0, 10), (10, 0)] {
+            let mut frame = Frame::new(Vec::new(), width, height, PixelFormat::Rgba);
+            for mode in [CropMode::Trim, CropMode::Fill([0, 0, 0, 255])] {
+                crop_filter(10.0, 10.0, 10.0, 10.0, mode).apply(&mut frame);
+                assert_eq!((frame.width, frame.height), (width, height));
+                assert!(frame.data.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn crop_mapping_round_trips() {
+        let filter = crop_filter(10.0, 30.0, 20.0, 20.0, CropMode::Trim);
+        assert_eq!(filter.map_forward(egui::pos2(0.1, 0.2)), egui::pos2(0.0, 0.0));
+        let point = egui::pos2(0.25, 0.75);
+        let back = filter.map_forward(filter.map_back(point));
+        assert!((back - point).length() < 1e-5);
+    }
+
+    #[test]
+    fn rotate_mapping_follows_the_pixels() {
+        for rotation in [Rotation::Deg90, Rotation::Deg180, Rotation::Deg270] {
+            let filter = RotateFilter { rotation };
+            let mut frame = coordinates(4, 2);
+            filter.clone().apply(&mut frame);
+            // Centre of source pixel (3, 0), then where it lands in the rotated frame
+            let source = egui::pos2(3.5 / 4.0, 0.5 / 2.0);
+            let target = filter.map_forward(source);
+            let (x, y) = ((target.x * frame.width as f32) as u32, (target.y * frame.height as f32) as u32);
+            assert_eq!(pixel(&frame, x, y), [3, 0, 0, 255], "{:?}", (x, y));
+            assert!((filter.map_back(target) - source).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn scale_fits_the_box() {
+        let mut filter = ScaleFilter::new();
+        filter.preset = ScalePreset::Custom;
+        filter.custom_size = [50, 50];
+        let mut frame = coordinates(200, 100);
+        filter.apply(&mut frame);
+        assert_eq!((frame.width, frame.height), (50, 25));
+        assert_eq!(frame.data.len(), 50 * 25 * 4);
+
+        // Frames that already fit are untouched
+        let mut small = coordinates(40, 20);
+        filter.apply(&mut small);
+        assert_eq!((small.width, small.height), (40, 20));
+    }
+
+    #[test]
+    fn mask_fills_its_region_only() {
+        let mut filter = MaskFilter::new();
+        filter.regions.push(MaskRegion { x: 0.5, y: 0.0, width: 0.5, height: 0.5, mode: MaskMode::Solid([9, 9, 9, 255]) });
+        let mut frame = coordinates(20, 20);
+        filter.apply(&mut frame);
+        assert_eq!(pixel(&frame, 10, 0), [9, 9, 9, 255]);
+        assert_eq!(pixel(&frame, 19, 9), [9, 9, 9, 255]);
+        assert_eq!(pixel(&frame, 9, 0), [9, 0, 0, 255]);
+        assert_eq!(pixel(&frame, 10, 10), [10, 10, 0, 255]);
+    }
+
+    #[test]
+    fn mask_pixelates_to_block_averages() {
+        let mut frame = coordinates(4, 4);
+        mask(&mut frame, &MaskRegion { x: 0.0, y: 0.0, width: 0.5, height: 0.5, mode: MaskMode::Pixelate(2) }).unwrap();
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(pixel(&frame, x, y), [0, 0, 0, 255]);
+        }
+        assert_eq!(pixel(&frame, 2, 2), [2, 2, 0, 255]);
+    }
+
+    #[test]
+    fn mask_fails_closed() {
+        let region = MaskRegion { x: 0.0, y: 0.0, width: 0.5, height: 0.5, mode: MaskMode::Solid([9, 9, 9, 255]) };
+        let mut bgra = Frame::with_stride(vec![200; 8 * 4 * 4], 4, 4, PixelFormat::Bgra, 32);
+        assert!(mask(&mut bgra, &region).is_err());
+
+        let mut filter = MaskFilter::new();
+        filter.regions.push(region);
+        filter.apply(&mut bgra);
+        assert_eq!(bgra.format, PixelFormat::Rgba);
+        assert!(bgra.data.chunks_exact(4).all(|pixel| pixel == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn chain_converts_only_when_needed() {
+        let bgra = Frame::with_stride(vec![7; 8 * 4 * 4], 4, 4, PixelFormat::Bgra, 32);
+
+        // The default chain does nothing yet, so the frame keeps its format and padding
+        let mut frame = bgra.clone();
+        apply_chain(&mut default_chain(), &mut frame);
+        assert_eq!((frame.format, frame.stride), (PixelFormat::Bgra, 32));
+
+        let mut chain = vec![FilterStage::new(Box::new(crop_filter(25.0, 0.0, 0.0, 0.0, CropMode::Trim)), true)];
+        let mut frame = bgra;
+        apply_chain(&mut chain, &mut frame);
+        assert_eq!((frame.format, frame.width, frame.stride), (PixelFormat::Rgba, 3, 12));
+        assert_eq!(pixel(&frame, 0, 0), [7, 7, 7, 255]);
+    }
+
+    #[test]
+    fn eraser_hits_lines_between_points() {
+        let mut filter = AnnotationFilter::new();
+        for (tool, from, to) in [
+            (AnnotationTool::Rectangle, egui::pos2(0.1, 0.1), egui::pos2(0.9, 0.9)),
+            (AnnotationTool::Arrow, egui::pos2(0.1, 0.5), egui::pos2(0.9, 0.5)),
+            (AnnotationTool::Pen, egui::pos2(0.2, 0.3), egui::pos2(0.8, 0.3)),
+        ] {
+            filter.tool = tool;
+            filter.preview_drag(PreviewDrag::Start(from));
+            filter.preview_drag(PreviewDrag::End(to));
+        }
+        filter.tool = AnnotationTool::Eraser;
+
+        // Inside the rectangle, away from every line
+        filter.preview_drag(PreviewDrag::Start(egui::pos2(0.5, 0.7)));
+        assert_eq!(filter.strokes.len(), 3);
+        // Middle of the pen line and the arrow shaft, far from their points
+        filter.preview_drag(PreviewDrag::Move(egui::pos2(0.5, 0.31)));
+        filter.preview_drag(PreviewDrag::Move(egui::pos2(0.5, 0.49)));
+        assert_eq!(filter.strokes.len(), 1);
+        // Right edge of the rectangle
+        filter.preview_drag(PreviewDrag::End(egui::pos2(0.91, 0.6)));
+        assert!(filter.strokes.is_empty());
+    }
+
+    #[test]
+    fn clear_drawings_reports_changes() {
+        let mut filter = AnnotationFilter::new();
+        assert!(!filter.clear_drawings());
+        filter.tool = AnnotationTool::Pen;
+        filter.preview_drag(PreviewDrag::Start(egui::pos2(0.5, 0.5)));
+        assert!(filter.clear_drawings());
+        assert!(filter.strokes.is_empty());
+    }
+
+    #[test]
+    fn mask_stays_on_the_source_pixels() {
+        let mut chain = default_chain();
+        assert!(chain[0].filter.pinned_first());
+        let mut filter = MaskFilter::new();
+        filter.regions.push(MaskRegion { x: 0.5, y: 0.0, width: 0.5, height: 0.5, mode: MaskMode::Solid([9, 9, 9, 255]) });
+        chain[0].filter = Box::new(filter);
+
+        // The crop changes after the region was placed; the region keeps covering source x >= 10, y < 10
+        for (left, top) in [(0.0, 0.0), (25.0, 0.0), (40.0, 30.0)] {
+            chain[1].filter = Box::new(crop_filter(left, 0.0, top, 0.0, CropMode::Trim));
+            let mut frame = coordinates(20, 20);
+            apply_chain(&mut chain, &mut frame);
+            let (dx, dy) = ((left / 5.0) as u32, (top / 5.0) as u32);
+            for y in 0..frame.height {
+                for x in 0..frame.width {
+                    let (sx, sy) = (x + dx, y + dy);
+                    let expected = if sx >= 10 && sy < 10 { [9, 9, 9, 255] } else { [sx as u8, sy as u8, 0, 255] };
+                    assert_eq!(pixel(&frame, x, y), expected, "{},{} with crop {},{}", x, y, left, top);
+                }
+            }
+        }
+    }
+}
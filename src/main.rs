@@ -4,13 +4,27 @@ mod caster;
 mod screen;
 mod client;
 mod server;
+mod pipeline;
+mod pattern;
+mod playback;
+mod text;
+mod filters;
+mod convert;
+mod dirty;
+mod compression;
+mod delta;
+#[cfg(target_os = "linux")]
+mod x11;
+#[cfg(target_os = "linux")]
+mod xshm;
+#[cfg(target_os = "linux")]
+mod virtual_display;
 
 fn main() {
-    let app = app::UStreamApp::default();
     // Run the egui application
     let _ = eframe::run_native(
         "UStream",
         eframe::NativeOptions::default(),
-        Box::new(|_cc| Ok(Box::new(app))), // No Result wrapper needed here
+        Box::new(|cc| Ok(Box::new(app::UStreamApp::new(cc)))),
     );
 }
@@ -0,0 +1,176 @@
+use std::thread;
+use tokio::sync::watch;
+use crate::screen::{Frame, FrameSource, Pacer};
+use crate::text::{draw_text, measure_text, utc_clock};
+use crate::convert::PixelFormat;
+
+// 75% SMPTE colour bars: white, yellow, cyan, green, magenta, red, blue
+const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+
+// Reverse-order castellations below the main bars
+const CASTELLATIONS: [[u8; 3]; 7] = [
+    [0, 0, 191],
+    [19, 19, 19],
+    [191, 0, 191],
+    [19, 19, 19],
+    [0, 191, 191],
+    [19, 19, 19],
+    [191, 191, 191],
+];
+
+// -I, 100% white, +Q, black, then the PLUGE (sub-black, black, super-black)
+const BOTTOM: [([u8; 3], u32); 7] = [
+    ([0, 33, 76], 5),
+    ([255, 255, 255], 5),
+    ([50, 0, 106], 5),
+    ([19, 19, 19], 6),
+    ([9, 9, 9], 1),
+    ([19, 19, 19], 1),
+    ([29, 29, 29], 5),
+];
+
+// Synthetic source producing colour bars with a moving box, a frame counter and
+// the wall-clock time burned in, for casting without a display
+pub struct TestPattern {
+    rx: watch::Receiver<Frame>,
+}
+
+impl TestPattern {
+    pub fn new(width: u32, height: u32, fps: u32) -> Self {
+        let width = width.max(64);
+        let height = height.max(64);
+        let (tx, rx) = watch::channel(Frame::empty());
+
+        thread::spawn(move || {
+            let mut pacer = Pacer::new(fps);
+            let mut counter: u64 = 0;
+            loop {
+                let frame = render_pattern(width, height, counter);
+                if tx.send(frame).is_err() {
+                    println!("Receiver has been dropped, stopping test pattern.");
+                    break;
+                }
+                counter += 1;
+
+                pacer.wait();
+            }
+        });
+
+        TestPattern { rx }
+    }
+}
+
+impl FrameSource for TestPattern {
+    fn receive_frame(&mut self) -> Option<Frame> {
+        if !self.rx.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let frame = self.rx.borrow_and_update();
+        if !frame.data.is_empty() {
+            Some(frame.clone())
+        } else {
+            None
+        }
+    }
+}
+
+fn fill_rect(frame: &mut Frame, x0: u32, y0: u32, x1: u32, y1: u32, color: [u8; 3]) {
+    let x1 = x1.min(frame.width);
+    let y1 = y1.min(frame.height);
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let index = ((y * frame.width + x) * 4) as usize;
+            frame.data[index..index + 4].copy_from_slice(&[color[0], color[1], color[2], 255]);
+        }
+    }
+}
+
+// Render one frame of the pattern; `counter` drives the animation and the burned-in number
+fn render_pattern(width: u32, height: u32, counter: u64) -> Frame {
+    let mut frame = Frame::new(vec![0; (width * height * 4) as usize], width, height, PixelFormat::Rgba);
+
+    let bars_bottom = height * 2 / 3;
+    let castellations_bottom = height * 3 / 4;
+    for (i, color) in BARS.iter().enumerate() {
+        let x0 = width * i as u32 / 7;
+        let x1 = width * (i as u32 + 1) / 7;
+        fill_rect(&mut frame, x0, 0, x1, bars_bottom, *color);
+        fill_rect(&mut frame, x0, bars_bottom, x1, castellations_bottom, CASTELLATIONS[i]);
+    }
+    let mut x0 = 0;
+    let mut units = 0;
+    let total_units: u32 = BOTTOM.iter().map(|(_, w)| w).sum();
+    for (color, w) in BOTTOM {
+        units += w;
+        let x1 = width * units / total_units;
+        fill_rect(&mut frame, x0, castellations_bottom, x1, height, color);
+        x0 = x1;
+    }
+
+    // Box bouncing across the bars, moving 1/120 of the width per frame; sized on the shorter
+    // side so it fits tall, narrow patterns too
+    let size = width.min(height) / 8;
+    let travel = width.saturating_sub(size) as u64;
+    let step = (width as u64 / 120).max(1);
+    let position = (counter * step) % (2 * travel.max(1));
+    let box_x = if position > travel { 2 * travel - position } else { position } as u32;
+    let box_y = (bars_bottom - size) / 2;
+    fill_rect(&mut frame, box_x, box_y, box_x + size, box_y + size, [255, 255, 255]);
+    fill_rect(&mut frame, box_x + size / 4, box_y + size / 4, box_x + size * 3 / 4, box_y + size * 3 / 4, [0, 0, 0]);
+
+    // Frame counter and UTC wall-clock time on a black strip
+    let label = format!("#{:08}  {} UTC", counter, utc_clock());
+    let text_size = (height as f32 / 16.0).max(10.0);
+    let (text_width, text_height) = measure_text(&label, text_size);
+    let text_x = width.saturating_sub(text_width) / 2;
+    let text_y = ((bars_bottom + castellations_bottom) / 2).saturating_sub(text_height / 2);
+    fill_rect(&mut frame, text_x.saturating_sub(8), text_y, text_x + text_width + 8, text_y + text_height, [0, 0, 0]);
+    draw_text(&mut frame, &label, text_x as i32, text_y as i32, text_size, [255, 255, 255, 255]);
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_any_size() {
+        for (width, height) in [(64, 4320), (7680, 64), (1, 1), (64, 64), (1280, 720)] {
+            let frame = render_pattern(width, height, 0);
+            assert_eq!((frame.width, frame.height), (width, height));
+            assert_eq!(frame.data.len(), (width * height * 4) as usize);
+        }
+    }
+
+    #[test]
+    fn counter_changes_the_output() {
+        for (width, height) in [(64, 4320), (7680, 64), (1280, 720)] {
+            let first = render_pattern(width, height, 0);
+            let second = render_pattern(width, height, 1);
+            assert!(first.data != second.data, "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    fn source_clamps_its_size() {
+        let mut pattern = TestPattern::new(1, 1, 30);
+        let started = std::time::Instant::now();
+        let frame = loop {
+            if let Some(frame) = pattern.receive_frame() {
+                break frame;
+            }
+            assert!(started.elapsed().as_secs() < 5, "No frame from the pattern");
+            thread::sleep(std::time::Duration::from_millis(5));
+        };
+        assert_eq!((frame.width, frame.height), (64, 64));
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::{mpsc, watch};
+use tokio::sync::mpsc::error::TryRecvError;
+use crate::screen::{FrameSource, Frame, Pacer, CursorOptions, message_slate};
+use crate::filters::{FilterStage, default_chain, apply_chain};
+use crate::dirty::DirtyTracker;
+use crate::server::StreamServer;
+use crate::compression::CompressionSettings;
+
+// Control messages sent from the UI to the pipeline thread
+pub enum Command {
+    Source(Box<dyn FrameSource>),
+    Stream(bool),
+    Filters(Vec<FilterStage>), // Replaces the whole filter chain
+    Fps(u32),
+    Cursor(CursorOptions),
+    Compression(CompressionSettings),
+    Disconnect,
+}
+
+// Handle to the background task that drives capture, filters and broadcast
+pub struct Pipeline {
+    commands: mpsc::UnboundedSender<Command>,
+    preview: watch::Receiver<Frame>,
+    client_count: Arc<AtomicUsize>,
+    achieved_fps: Arc<AtomicU32>, // f32 bits of the measured broadcast rate
+    changed_area: Arc<AtomicU32>, // f32 bits of the average damaged fraction, NaN if unknown
+    compression_ratio: Arc<AtomicU32>, // f32 bits, NaN while nothing is compressed
+    compression_cost: Arc<AtomicU32>, // f32 bits of the milliseconds spent per compressed frame
+}
+
+pub const DEFAULT_FPS: u32 = 30;
+
+impl Pipeline {
+    // Start the server and the pipeline thread; frames flow once a source is set
+    pub fn new() -> Self {
+        let (commands, mut command_rx) = mpsc::unbounded_channel();
+        let (preview_tx, preview) = watch::channel(Frame::empty());
+        let mut server = StreamServer::new();
+        let client_count = server.client_counter();
+        let achieved_fps = Arc::new(AtomicU32::new(0));
+        let measured_fps = Arc::clone(&achieved_fps);
+        let changed_area = Arc::new(AtomicU32::new(f32::NAN.to_bits()));
+        let measured_area = Arc::clone(&changed_area);
+        let compression_ratio = Arc::new(AtomicU32::new(f32::NAN.to_bits()));
+        let measured_ratio = Arc::clone(&compression_ratio);
+        let compression_cost = Arc::new(AtomicU32::new(f32::NAN.to_bits()));
+        let measured_cost = Arc::clone(&compression_cost);
+        server.set_fps(DEFAULT_FPS);
+
+        thread::spawn(move || {
+            let mut source: Option<Box<dyn FrameSource>> = None;
+            let mut last_size = None; // Size of the last processed frame
+            let mut input: Option<Frame> = None; // Latest source frame, before the filters
+            let mut output: Option<Frame> = None; // What receivers get until something changes
+            let mut filters = default_chain();
+            let mut filters_changed = false;
+            let mut dirty = DirtyTracker::new();
+            let mut is_streaming = false;
+
+            let mut fps = DEFAULT_FPS;
+            let mut cursor = CursorOptions::new();
+            let mut pacer = Pacer::new(fps);
+            let mut sent_frames = 0;
+            let mut damaged_fraction = 0.0;
+            let mut damaged_frames = 0;
+            let mut window_start = Instant::now();
+            loop {
+                // Apply every pending command before processing the next frame
+                loop {
+                    match command_rx.try_recv() {
+                        Ok(Command::Source(mut new_source)) => {
+                            // Stop the old source before the new one grabs the display
+                            drop(source.take());
+                            new_source.set_fps(fps);
+                            new_source.set_cursor(cursor);
+                            source = Some(new_source);
+                            // Sent until the new source delivers
+                            input = None;
+                            output = last_size.map(|size| message_slate(size, "Switching source..."));
+                        }
+                        Ok(Command::Stream(value)) => is_streaming = value,
+                        Ok(Command::Filters(chain)) => {
+                            match chain.iter().try_for_each(|stage| stage.filter.validate()) {
+                                Ok(()) => {
+                                    filters = chain;
+                                    filters_changed = true;
+                                }
+                                Err(e) => eprintln!("Ignoring invalid filter chain: {}", e),
+                            }
+                        }
+                        Ok(Command::Fps(value)) => {
+                            fps = value.max(1);
+                            pacer.set_fps(fps);
+                            server.set_fps(fps);
+                            if let Some(source) = &mut source {
+                                source.set_fps(fps);
+                            }
+                        }
+                        Ok(Command::Cursor(options)) => {
+                            cursor = options;
+                            if let Some(source) = &mut source {
+                                source.set_cursor(cursor);
+                            }
+                        }
+                        Ok(Command::Compression(settings)) => server.set_compression(settings),
+                        Ok(Command::Disconnect) => {
+                            is_streaming = false;
+                            server.disconnect();
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            println!("Caster has been dropped, stopping pipeline.");
+                            return;
+                        }
+                    }
+                }
+
+                if let Some(source) = &mut source {
+                    let fresh = match source.receive_frame() {
+                        Some(frame) => {
+                            // Damage is relative to the source frame, before any filter moves pixels
+                            if let Some(damage) = source.take_damage() {
+                                let area: u64 = damage.iter().map(|rect| rect.width as u64 * rect.height as u64).sum();
+                                let total = (frame.width as u64 * frame.height as u64).max(1);
+                                damaged_fraction += (area as f32 / total as f32).min(1.0);
+                                damaged_frames += 1;
+                            }
+                            input = Some(frame);
+                            true
+                        }
+                        None => false,
+                    };
+                    // An idle source costs nothing, unless the filters have something new to draw
+                    let refilter = filters_changed || filters.iter().any(|stage| stage.enabled && stage.filter.animated());
+                    if let Some(input) = input.as_ref().filter(|_| fresh || refilter) {
+                        let mut frame = input.clone();
+                        apply_chain(&mut filters, &mut frame);
+                        filters_changed = false;
+                        last_size = Some((frame.width, frame.height));
+                        // Compared after the filters, on what receivers actually get
+                        frame.damage = Some(dirty.update(&frame));
+                        let _ = preview_tx.send(frame.clone());
+                        output = Some(frame);
+                    }
+                    if let Some(frame) = &mut output {
+                        if server.broadcast_frame(frame, is_streaming) {
+                            sent_frames += 1;
+                        }
+                        // The server has taken note of the changes, later ticks only keep the stream alive
+                        frame.damage = Some(Vec::new());
+                    }
+                }
+
+                // Publish the achieved broadcast rate once per second
+                let elapsed = window_start.elapsed().as_secs_f32();
+                if elapsed >= 1.0 {
+                    measured_fps.store((sent_frames as f32 / elapsed).to_bits(), Ordering::Relaxed);
+                    let average = if damaged_frames > 0 { damaged_fraction / damaged_frames as f32 } else { f32::NAN };
+                    measured_area.store(average.to_bits(), Ordering::Relaxed);
+                    let (ratio, cost) = server.take_compression_stats().unwrap_or((f32::NAN, f32::NAN));
+                    measured_ratio.store(ratio.to_bits(), Ordering::Relaxed);
+                    measured_cost.store(cost.to_bits(), Ordering::Relaxed);
+                    sent_frames = 0;
+                    damaged_fraction = 0.0;
+                    damaged_frames = 0;
+                    window_start = Instant::now();
+                }
+
+                pacer.wait();
+            }
+        });
+
+        Self { commands, preview, client_count, achieved_fps, changed_area, compression_ratio, compression_cost }
+    }
+
+    pub fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            eprintln!("Pipeline has stopped, command ignored.");
+        }
+    }
+
+    // Latest processed frame, for display in the caster UI
+    pub fn preview(&self) -> Option<Frame> {
+        let frame = self.preview.borrow();
+        if !frame.data.is_empty() {
+            Some(frame.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn get_client_count(&self) -> usize {
+        self.client_count.load(Ordering::SeqCst)
+    }
+
+    // Frames per second actually broadcast over the last second
+    pub fn get_achieved_fps(&self) -> f32 {
+        f32::from_bits(self.achieved_fps.load(Ordering::Relaxed))
+    }
+
+    // Average fraction of the source frame that changed over the last second, for sources reporting damage
+    pub fn get_changed_area(&self) -> Option<f32> {
+        let area = f32::from_bits(self.changed_area.load(Ordering::Relaxed));
+        if area.is_nan() {
+            None
+        } else {
+            Some(area)
+        }
+    }
+
+    // Compression ratio and milliseconds of CPU per frame over the last second, None when
+    // nothing was compressed
+    pub fn get_compression_stats(&self) -> Option<(f32, f32)> {
+        let ratio = f32::from_bits(self.compression_ratio.load(Ordering::Relaxed));
+        let cost = f32::from_bits(self.compression_cost.load(Ordering::Relaxed));
+        if ratio.is_nan() {
+            None
+        } else {
+            Some((ratio, cost))
+        }
+    }
+}
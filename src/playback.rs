@@ -0,0 +1,396 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::watch;
+use crate::screen::{Frame, FrameSource, Pacer};
+use crate::convert::{PixelFormat, yuv_to_rgba};
+
+// What a playback source reads from
+enum Media {
+    Images(Vec<PathBuf>), // Sorted image files, one frame each
+    Y4m(PathBuf),
+}
+
+// Source replaying a directory of PNG/JPEG files or a raw .y4m video
+pub struct FilePlayback {
+    rx: watch::Receiver<Frame>,
+}
+
+impl FilePlayback {
+    // `fps` overrides the rate stored in a .y4m header; image sequences default to 30 fps.
+    // Without `looping` the last frame stays on screen once the media ends.
+    pub fn open(path: &Path, fps: Option<u32>, looping: bool) -> Result<Self, String> {
+        let (media, native_fps) = if path.is_dir() {
+            (Media::Images(list_images(path)?), 30.0)
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("y4m")) {
+            let mut reader = Y4mReader::open(path)?;
+            // Looping an empty file would reopen it on every tick
+            if reader.next_frame()?.is_none() {
+                return Err(format!("{} has no frames", path.display()));
+            }
+            (Media::Y4m(path.to_path_buf()), reader.fps)
+        } else {
+            return Err(format!("Unsupported media: {}", path.display()));
+        };
+        let fps = fps.map(|fps| fps.max(1) as f64).unwrap_or(native_fps);
+
+        let (tx, rx) = watch::channel(Frame::empty());
+
+        thread::spawn(move || {
+            let mut pacer = Pacer::with_interval(Duration::from_secs_f64(1.0 / fps));
+            let mut next_image = 0;
+            let mut y4m: Option<Y4mReader> = None;
+            loop {
+                let frame = match &media {
+                    Media::Images(images) => {
+                        if next_image == images.len() {
+                            if !looping {
+                                break;
+                            }
+                            next_image = 0;
+                        }
+                        let path = &images[next_image];
+                        next_image += 1;
+                        match image::open(path) {
+                            Ok(image) => {
+                                let image = image.to_rgba8();
+                                let (width, height) = (image.width(), image.height());
+                                Some(Frame::new(image.into_raw(), width, height, PixelFormat::Rgba))
+                            }
+                            Err(e) => {
+                                eprintln!("Skipping {}: {}", path.display(), e);
+                                None
+                            }
+                        }
+                    }
+                    Media::Y4m(path) => {
+                        let reopened = y4m.is_none();
+                        if reopened {
+                            match Y4mReader::open(path) {
+                                Ok(reader) => y4m = Some(reader),
+                                Err(e) => {
+                                    eprintln!("Error reopening {}: {}", path.display(), e);
+                                    break;
+                                }
+                            }
+                        }
+                        let Some(reader) = y4m.as_mut() else {
+                            break;
+                        };
+                        match reader.next_frame() {
+                            Ok(Some(frame)) => Some(frame),
+                            Ok(None) => {
+                                if !looping {
+                                    break;
+                                }
+                                if reopened {
+                                    eprintln!("{} has no frames left, stopping playback.", path.display());
+                                    break;
+                                }
+                                y4m = None; // Reopen right away, the first frame is due on this tick
+                                continue;
+                            }
+                            Err(e) => {
+                                eprintln!("Error reading {}: {}", path.display(), e);
+                                break;
+                            }
+                        }
+                    }
+                };
+
+                if let Some(frame) = frame {
+                    if tx.send(frame).is_err() {
+                        println!("Receiver has been dropped, stopping playback.");
+                        break;
+                    }
+                }
+
+                pacer.wait();
+            }
+            println!("Playback finished.");
+        });
+
+        Ok(FilePlayback { rx })
+    }
+}
+
+impl FrameSource for FilePlayback {
+    fn receive_frame(&mut self) -> Option<Frame> {
+        if !self.rx.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let frame = self.rx.borrow_and_update();
+        if !frame.data.is_empty() {
+            Some(frame.clone())
+        } else {
+            None
+        }
+    }
+}
+
+// PNG and JPEG files of a directory, in file name order
+fn list_images(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Cannot read {}: {}", dir.display(), e))?;
+    let mut images: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+        })
+        .collect();
+    if images.is_empty() {
+        return Err(format!("No PNG or JPEG files in {}", dir.display()));
+    }
+    images.sort();
+    Ok(images)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Chroma {
+    C420,
+    C422,
+    C444,
+    Mono,
+}
+
+// Sequential reader for 8-bit YUV4MPEG2 files
+struct Y4mReader {
+    reader: BufReader<File>,
+    width: u32,
+    height: u32,
+    chroma: Chroma,
+    fps: f64,
+}
+
+impl Y4mReader {
+    fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|e| format!("Cannot read y4m header: {}", e))?;
+
+        let mut tokens = header.split_ascii_whitespace();
+        if tokens.next() != Some("YUV4MPEG2") {
+            return Err(format!("{} is not a YUV4MPEG2 file", path.display()));
+        }
+        let (mut width, mut height, mut fps, mut chroma) = (0, 0, 30.0, Chroma::C420);
+        for token in tokens {
+            let mut chars = token.chars();
+            let key = chars.next();
+            let value = chars.as_str();
+            match key {
+                Some('W') => width = value.parse().map_err(|_| format!("Invalid width: {}", value))?,
+                Some('H') => height = value.parse().map_err(|_| format!("Invalid height: {}", value))?,
+                Some('F') => {
+                    if let Some((num, den)) = value.split_once(':') {
+                        let num: f64 = num.parse().unwrap_or(0.0);
+                        let den: f64 = den.parse().unwrap_or(0.0);
+                        if num > 0.0 && den > 0.0 {
+                            fps = num / den;
+                        }
+                    }
+                }
+                Some('C') => {
+                    chroma = match value {
+                        // Only 8-bit samples, deeper tags such as 420p10 take two bytes each
+                        "420" | "420jpeg" | "420mpeg2" | "420paldv" => Chroma::C420,
+                        "422" => Chroma::C422,
+                        "444" => Chroma::C444,
+                        "mono" => Chroma::Mono,
+                        other => return Err(format!("Unsupported y4m colorspace: {}", other)),
+                    }
+                }
+                _ => {} // Interlacing, aspect ratio and extensions don't affect decoding
+            }
+        }
+        if width == 0 || height == 0 {
+            return Err("Missing y4m frame size".to_string());
+        }
+
+        Ok(Self { reader, width, height, chroma, fps })
+    }
+
+    // Next frame, as is for 4:2:0 files and converted to RGBA otherwise; None at the end of the file
+    fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        let mut frame_header = String::new();
+        let read = self.reader.read_line(&mut frame_header).map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if !frame_header.starts_with("FRAME") {
+            return Err("Missing FRAME marker".to_string());
+        }
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let (chroma_width, chroma_height) = match self.chroma {
+            Chroma::C420 => (width.div_ceil(2), height.div_ceil(2)),
+            Chroma::C422 => (width.div_ceil(2), height),
+            Chroma::C444 => (width, height),
+            Chroma::Mono => (0, 0),
+        };
+        let mut planes = vec![0u8; width * height + 2 * chroma_width * chroma_height];
+        self.reader.read_exact(&mut planes).map_err(|e| format!("Truncated frame: {}", e))?;
+        if self.chroma == Chroma::C420 {
+            // Already laid out as our Yuv420 frames
+            return Ok(Some(Frame::new(planes, self.width, self.height, PixelFormat::Yuv420)));
+        }
+        let (luma, chroma) = planes.split_at(width * height);
+        let (u_plane, v_plane) = chroma.split_at(chroma_width * chroma_height);
+
+        let mut data = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let luma_value = luma[y * width + x];
+                let (u, v) = match self.chroma {
+                    Chroma::Mono => (128, 128),
+                    _ => {
+                        let cx = x * chroma_width / width;
+                        let cy = y * chroma_height / height;
+                        (u_plane[cy * chroma_width + cx], v_plane[cy * chroma_width + cx])
+                    }
+                };
+                data.extend_from_slice(&yuv_to_rgba(luma_value, u, v));
+            }
+        }
+
+        Ok(Some(Frame::new(data, self.width, self.height, PixelFormat::Rgba)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Write a y4m file made of `frames`, each holding its planes back to back
+    fn write_y4m(name: &str, header: &str, frames: &[Vec<u8>]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ustream-playback-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let mut data = format!("{}\n", header).into_bytes();
+        for frame in frames {
+            data.extend_from_slice(b"FRAME\n");
+            data.extend_from_slice(frame);
+        }
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    // 4x2 4:2:0 frame: luma from `base` upwards, one chroma sample per 2x2 block
+    fn frame_420(base: u8) -> Vec<u8> {
+        let mut planes: Vec<u8> = (0..8).map(|i| base + i * 10).collect();
+        planes.extend_from_slice(&[100, 200, 150, 60]);
+        planes
+    }
+
+    fn rgba(frame: &Frame) -> Vec<u8> {
+        let mut frame = frame.clone();
+        frame.convert(PixelFormat::Rgba).unwrap();
+        frame.data
+    }
+
+    // Latest frame of the playback, waiting up to a few seconds for one
+    fn next(playback: &mut FilePlayback) -> Option<Frame> {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            if let Some(frame) = playback.receive_frame() {
+                return Some(frame);
+            }
+            if playback.rx.has_changed().is_err() {
+                return None; // Playback finished
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("Playback stalled");
+    }
+
+    #[test]
+    fn decodes_420() {
+        let path = write_y4m("420.y4m", "YUV4MPEG2 W4 H2 F25:1 C420jpeg", &[frame_420(20)]);
+        let mut reader = Y4mReader::open(&path).unwrap();
+        assert_eq!(reader.fps, 25.0);
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!((frame.width, frame.height, frame.format), (4, 2, PixelFormat::Yuv420));
+
+        let expected: Vec<u8> = (0..8u8)
+            .flat_map(|i| {
+                let (u, v) = if i % 4 < 2 { (100, 150) } else { (200, 60) };
+                yuv_to_rgba(20 + i * 10, u, v)
+            })
+            .collect();
+        assert_eq!(rgba(&frame), expected);
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn decodes_444_and_mono() {
+        let planes: Vec<u8> = (0..24).map(|i| 16 + i * 9).collect();
+        let path = write_y4m("444.y4m", "YUV4MPEG2 W4 H2 C444", std::slice::from_ref(&planes));
+        let frame = Y4mReader::open(&path).unwrap().next_frame().unwrap().unwrap();
+        assert_eq!(frame.format, PixelFormat::Rgba);
+        let expected: Vec<u8> = (0..8).flat_map(|i| yuv_to_rgba(planes[i], planes[8 + i], planes[16 + i])).collect();
+        assert_eq!(frame.data, expected);
+
+        let path = write_y4m("mono.y4m", "YUV4MPEG2 W4 H2 Cmono", &[planes[..8].to_vec()]);
+        let frame = Y4mReader::open(&path).unwrap().next_frame().unwrap().unwrap();
+        let expected: Vec<u8> = planes[..8].iter().flat_map(|&luma| yuv_to_rgba(luma, 128, 128)).collect();
+        assert_eq!(frame.data, expected);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        for header in [
+            "YUV4MPEG W4 H2",
+            "YUV4MPEG2 H2",
+            "YUV4MPEG2 W4 H0",
+            "YUV4MPEG2 Wfour H2",
+            "YUV4MPEG2 W4 H2 C420p10",
+            "YUV4MPEG2 W4 H2 C444p16",
+            "YUV4MPEG2 W4 H2 C411",
+        ] {
+            let path = write_y4m("bad.y4m", header, &[]);
+            assert!(Y4mReader::open(&path).is_err(), "{}", header);
+        }
+        let path = write_y4m("truncated.y4m", "YUV4MPEG2 W4 H2", &[vec![0; 5]]);
+        assert!(Y4mReader::open(&path).unwrap().next_frame().is_err());
+    }
+
+    #[test]
+    fn refuses_files_without_frames() {
+        let path = write_y4m("empty.y4m", "YUV4MPEG2 W4 H2", &[]);
+        assert!(FilePlayback::open(&path, None, true).is_err());
+    }
+
+    #[test]
+    fn stops_at_the_end() {
+        let path = write_y4m("stop.y4m", "YUV4MPEG2 W4 H2", &[frame_420(10), frame_420(20)]);
+        let mut playback = FilePlayback::open(&path, Some(100), false).unwrap();
+        let mut last = None;
+        while let Some(frame) = next(&mut playback) {
+            last = Some(frame.data);
+        }
+        // The last frame stays on screen once the file ends
+        assert_eq!(last, Some(frame_420(20)));
+        assert_eq!(playback.rx.borrow().data, frame_420(20));
+    }
+
+    #[test]
+    fn loops_back_to_the_start() {
+        let path = write_y4m("loop.y4m", "YUV4MPEG2 W4 H2", &[frame_420(10), frame_420(20)]);
+        let mut playback = FilePlayback::open(&path, Some(100), true).unwrap();
+        let mut seen_end = false;
+        loop {
+            let frame = next(&mut playback).expect("Looping playback finished");
+            if frame.data == frame_420(20) {
+                seen_end = true;
+            } else if seen_end {
+                assert_eq!(frame.data, frame_420(10));
+                break;
+            }
+        }
+    }
+}
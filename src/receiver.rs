@@ -3,7 +3,13 @@ use crate::client::{DisconnectHandle,connect_to_server};
 use tokio::sync::mpsc;
 use tokio::runtime::Runtime;
 use std::sync::Arc;
-use crate::screen::{Frame};
+use std::time::{Duration, Instant};
+use crate::screen::{DamageRect, Frame};
+use crate::convert::to_rgba;
+use crate::delta::{DeltaDecoder, FrameUpdate};
+
+// Keyframe requests are repeated this often until one arrives
+const KEYFRAME_RETRY: Duration = Duration::from_secs(1);
 
 pub struct Receiver {
     ip_address: String,
@@ -11,8 +17,10 @@ pub struct Receiver {
     error_message: Option<String>,
     disconnect_handle: Option<DisconnectHandle>,
     runtime: Arc<Runtime>,
-    frame_receiver: Option<mpsc::Receiver<Option<Frame>>>,
-    current_frame: Option<Frame>,
+    frame_receiver: Option<mpsc::Receiver<Option<FrameUpdate>>>,
+    decoder: DeltaDecoder, // Persistent framebuffer patched by the caster's updates
+    texture: Option<egui::TextureHandle>,
+    keyframe_requested: Option<Instant>, // Last time we asked for a keyframe, while out of sync
 }
 
 impl Receiver {
@@ -26,7 +34,9 @@ impl Receiver {
             disconnect_handle: None,
             runtime,
             frame_receiver: None,
-            current_frame: None,
+            decoder: DeltaDecoder::new(),
+            texture: None,
+            keyframe_requested: None,
         }
     }
 
@@ -72,35 +82,47 @@ impl Receiver {
 
         ui.add_space(20.0);
 
-        // Display received frames if connected
+        // Apply every update received since the last repaint, in order
+        let mut replaced = false;
+        let mut patched = Vec::new();
         if self.connected {
             if let Some(frame_rx) = &mut self.frame_receiver {
-                if let Ok(frame) = frame_rx.try_recv() {
-                    match frame{
-                        Some(frame) => {
-                            self.current_frame = Some(frame.clone());
-                        }
+                while let Ok(update) = frame_rx.try_recv() {
+                    match update {
+                        Some(update) => match self.decoder.apply(update) {
+                            Ok(Some(tiles)) => patched.extend(tiles),
+                            Ok(None) => {
+                                replaced = true;
+                                self.keyframe_requested = None;
+                            }
+                            Err(e) => {
+                                // Out of sync: ask for a full frame, once in a while
+                                if self.keyframe_requested.is_none_or(|at| at.elapsed() >= KEYFRAME_RETRY) {
+                                    eprintln!("{}, requesting a keyframe", e);
+                                    if let Some(handle) = &self.disconnect_handle {
+                                        handle.request_keyframe();
+                                    }
+                                    self.keyframe_requested = Some(Instant::now());
+                                }
+                            }
+                        },
                         None => {
                             println!("Connection closed by server, stopping receiver.");
                             self.connected = false;
-                            self.current_frame = None;
+                            self.decoder = DeltaDecoder::new();
+                            self.texture = None;
+                            break;
                         }
                     }
                 }
             }
         }
+        self.update_texture(ctx, replaced, &patched);
 
-        if let Some(frame) = &self.current_frame {
+        if let (Some(frame), Some(image_handle)) = (self.decoder.frame(), &self.texture) {
             let width = frame.width as usize;
             let height = frame.height as usize;
 
-            // Convert the raw frame data to an egui-compatible image
-            let texture = egui::ColorImage::from_rgba_unmultiplied(
-                [width, height],
-                &frame.data,
-            );
-            let image_handle = ctx.load_texture("screen_frame", texture, Default::default());
-
             // Determine available space and aspect ratio
             let mut available_size = ui.available_size();
             available_size.x -= 10.0;
@@ -115,12 +137,42 @@ impl Receiver {
             };
 
             // Display the image
-            ui.add(egui::Image::new(&image_handle).fit_to_exact_size(target_size));
+            ui.add(egui::Image::new(image_handle).fit_to_exact_size(target_size));
         } else {
             ui.label("No frame available.");
         }
     }
 
+    // Bring the texture up to date with the framebuffer: everything after a new frame, otherwise
+    // only the patched tiles. Frames keep the caster's pixel format, textures take RGBA.
+    fn update_texture(&mut self, ctx: &egui::Context, replaced: bool, patched: &[DamageRect]) {
+        let Some(frame) = self.decoder.frame() else {
+            self.texture = None;
+            return;
+        };
+        let size = [frame.width as usize, frame.height as usize];
+        match &mut self.texture {
+            Some(texture) if !replaced && texture.size() == size => {
+                for rect in patched {
+                    match area_rgba(frame, rect) {
+                        Ok(rgba) => {
+                            let image = egui::ColorImage::from_rgba_unmultiplied([rect.width as usize, rect.height as usize], &rgba);
+                            texture.set_partial([rect.x as usize, rect.y as usize], image, Default::default());
+                        }
+                        Err(e) => eprintln!("Cannot show received tile: {}", e),
+                    }
+                }
+            }
+            _ => match frame.to_rgba() {
+                Ok(rgba) => {
+                    let image = egui::ColorImage::from_rgba_unmultiplied(size, &rgba);
+                    self.texture = Some(ctx.load_texture("screen_frame", image, Default::default()));
+                }
+                Err(e) => eprintln!("Cannot show received frame: {}", e),
+            },
+        }
+    }
+
     fn handle_connect(&mut self) {
         // Clear any previous errors
         self.error_message = None;
@@ -156,6 +208,15 @@ impl Receiver {
             println!("Disconnected");
         }
         self.connected = false;
-        self.current_frame = None;
+        self.decoder = DeltaDecoder::new();
+        self.texture = None;
     }
+}
+
+// Part of a packed frame as RGBA
+fn area_rgba(frame: &Frame, rect: &DamageRect) -> Result<Vec<u8>, String> {
+    let bpp = frame.format.bytes_per_pixel().ok_or("Tiles of a planar frame")?;
+    let start = rect.y as usize * frame.stride + rect.x as usize * bpp;
+    let data = frame.data.get(start..).ok_or("Tile outside the frame")?;
+    to_rgba(data, frame.format, frame.stride, rect.width, rect.height)
 }
\ No newline at end of file
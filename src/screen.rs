@@ -1,8 +1,14 @@
 use scrap::{Capturer, Display};
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::watch;
 use serde::{Deserialize, Serialize};
+use image::RgbaImage;
+use crate::text::{draw_text, measure_text};
+use crate::convert::{PixelFormat, copy_rows, from_rgba, to_rgba};
+use image::imageops::{self, FilterType};
 
 pub fn available_displays() -> Vec<String> {
     let displays: Vec<String> = Display::all()
@@ -13,157 +19,1095 @@ pub fn available_displays() -> Vec<String> {
     displays
 }
 
-fn convert_bgra_to_rgba(frame: &[u8], width: u32, height: u32) -> Vec<u8> {
-    let h = height as usize;
-    let w = width as usize;
-    let stride = frame.len() / h; 
-    let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Frame{
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height : u32,
+    pub format: PixelFormat,
+    pub stride: usize, // Bytes per row of the first plane, rows may be padded
+    // Areas changed since the previous frame of the stream, empty if none; None if unknown
+    #[serde(skip)]
+    pub damage: Option<Vec<DamageRect>>,
+}
+
+impl Frame {
+    // Frame with unpadded rows
+    pub fn new(data: Vec<u8>, width: u32, height: u32, format: PixelFormat) -> Self {
+        Self { data, width, height, format, stride: format.packed_stride(width), damage: None }
+    }
+
+    // Frame whose first plane rows are `stride` bytes apart
+    pub fn with_stride(data: Vec<u8>, width: u32, height: u32, format: PixelFormat, stride: usize) -> Self {
+        Self { data, width, height, format, stride, damage: None }
+    }
+
+    pub fn empty() -> Self {
+        Self::new(vec![], 0, 0, PixelFormat::Rgba)
+    }
+
+    // Whether rows are unpadded and the data holds exactly the image
+    pub fn is_packed(&self) -> bool {
+        self.stride == self.format.packed_stride(self.width) && self.data.len() == self.format.buffer_len(self.stride, self.height)
+    }
 
-    for y in 0..h {
-        for x in 0..w {
-            let i = stride * y + 4 * x;
-            rgba_data.extend_from_slice(&[
-                frame[i + 2],
-                frame[i + 1],
-                frame[i],
-                255,
-            ]);
+    // Pixels as tightly packed RGBA, e.g. for a texture upload
+    pub fn to_rgba(&self) -> Result<Vec<u8>, String> {
+        if self.format == PixelFormat::Rgba && self.is_packed() {
+            return Ok(self.data.clone());
         }
+        to_rgba(&self.data, self.format, self.stride, self.width, self.height)
     }
 
-    rgba_data
+    // Convert in place to `format` with unpadded rows; does nothing if already there
+    pub fn convert(&mut self, format: PixelFormat) -> Result<(), String> {
+        if self.format == format && self.is_packed() {
+            return Ok(());
+        }
+        let rgba = self.to_rgba()?;
+        self.data = from_rgba(&rgba, self.width, self.height, format)?;
+        self.format = format;
+        self.stride = format.packed_stride(self.width);
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Frame{
-    pub data: Vec<u8>,
+// Keeps the buffer of the last frame sent once receivers copied it out, so the next frame
+// only rewrites what changed instead of allocating and copying the whole picture
+#[derive(Default)]
+pub struct FrameRecycler {
+    spare: Vec<u8>,
+}
+
+impl FrameRecycler {
+    // Hold on to the frame a channel handed back; it must be the last frame sent
+    pub fn recycle(&mut self, frame: Frame) {
+        self.spare = if frame.format == PixelFormat::Bgra { frame.data } else { Vec::new() };
+    }
+
+    // Buffer of `len` bytes for a canvas that gets rewritten whole
+    pub fn take(&mut self, len: usize) -> Vec<u8> {
+        let mut buffer = std::mem::take(&mut self.spare);
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    // Copy of a BGRA `canvas`, only the `changed` areas are copied when the spare holds the
+    // previous frame of the same size; None copies everything
+    pub fn copy(&mut self, canvas: &[u8], stride: usize, changed: Option<&[DamageRect]>) -> Vec<u8> {
+        let mut buffer = std::mem::take(&mut self.spare);
+        match changed {
+            Some(changed) if buffer.len() == canvas.len() => {
+                for rect in changed {
+                    let start = rect.y as usize * stride + rect.x as usize * 4;
+                    let row_len = rect.width as usize * 4;
+                    copy_rows(&canvas[start..], stride, &mut buffer[start..], stride, row_len, rect.height as usize);
+                }
+            }
+            _ => {
+                buffer.clear();
+                buffer.extend_from_slice(canvas);
+            }
+        }
+        buffer
+    }
+}
+
+// Part of an (x0, y0, x1, y1) area inside a width x height frame
+pub fn clip_area(area: (i32, i32, i32, i32), width: u32, height: u32) -> Option<DamageRect> {
+    let x0 = area.0.clamp(0, width as i32);
+    let y0 = area.1.clamp(0, height as i32);
+    let x1 = area.2.clamp(0, width as i32);
+    let y1 = area.3.clamp(0, height as i32);
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+    Some(DamageRect { x: x0 as u32, y: y0 as u32, width: (x1 - x0) as u32, height: (y1 - y0) as u32 })
+}
+
+// Anything that can feed frames to the caster pipeline
+pub trait FrameSource: Send {
+    // Frame produced since the last call, None if nothing new arrived; the pipeline keeps
+    // using the previous one meanwhile
+    fn receive_frame(&mut self) -> Option<Frame>;
+
+    // Target production rate; sources with a fixed rate ignore it
+    fn set_fps(&mut self, _fps: u32) {}
+
+    // How the mouse pointer is drawn; sources without a pointer ignore it
+    fn set_cursor(&mut self, _options: CursorOptions) {}
+
+    // Areas that changed in the frame last returned by receive_frame, None if the source can't tell
+    fn take_damage(&mut self) -> Option<Vec<DamageRect>> {
+        None
+    }
+
+    // Health of the capture behind the source, for sources that can fail at runtime
+    fn state(&self) -> Option<watch::Receiver<CaptureState>> {
+        None
+    }
+}
+
+// Changed area of a frame, in pixels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DamageRect {
+    pub x: u32,
+    pub y: u32,
     pub width: u32,
-    pub height : u32
+    pub height: u32,
+}
+
+// Pointer compositing options for screen sources
+#[derive(Clone, Copy, PartialEq)]
+pub struct CursorOptions {
+    pub show: bool,
+    pub scale: f32,    // Enlarge the pointer image
+    pub halo: bool,    // Translucent highlight around the pointer
+    pub ripples: bool, // Expanding rings on mouse clicks
 }
+
+impl CursorOptions {
+    pub fn new() -> Self {
+        Self { show: true, scale: 1.0, halo: false, ripples: false }
+    }
+}
+
+// Deadline-based frame pacing: waits for the next tick instead of sleeping
+// a fixed time after the work, so processing time doesn't lower the rate
+pub struct Pacer {
+    interval: Duration,
+    deadline: Instant,
+}
+
+impl Pacer {
+    pub fn new(fps: u32) -> Self {
+        Self::with_interval(Duration::from_secs_f64(1.0 / fps.max(1) as f64))
+    }
+
+    pub fn with_interval(interval: Duration) -> Self {
+        Self { interval, deadline: Instant::now() }
+    }
+
+    pub fn set_fps(&mut self, fps: u32) {
+        self.interval = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+    }
+
+    pub fn wait(&mut self) {
+        self.deadline += self.interval;
+        let now = Instant::now();
+        if self.deadline > now {
+            thread::sleep(self.deadline - now);
+        } else {
+            // Running late, don't try to catch up with a burst of frames
+            self.deadline = now;
+        }
+    }
+}
+
+// Health of a screen capture, reported to the UI
+#[derive(Clone, PartialEq)]
+pub enum CaptureState {
+    Starting,
+    Running,
+    Restarting { attempt: u32, reason: String },
+    Failed(String),
+}
+
+pub const MAX_CAPTURE_RETRIES: u32 = 8;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
 pub struct ScreenCapture {
     pub rx: watch::Receiver<Frame>,
+    fps: Arc<AtomicU32>, // Shared with the capture thread
+    cursor: watch::Sender<CursorOptions>,
+    state: watch::Receiver<CaptureState>,
+    running: Arc<AtomicBool>, // Cleared to stop the capture thread
+    thread: Option<JoinHandle<()>>,
+}
+
+// How the cropped margins are handled
+#[derive(Clone, Copy, PartialEq)]
+pub enum CropMode {
+    Trim,           // Drop the margins, the frame gets smaller
+    Fill([u8; 4]),  // Keep the full size and paint the margins with an RGBA colour
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct CropValues {
     pub left: f32,
     pub right: f32,
     pub top: f32,
     pub bottom: f32,
+    pub mode: CropMode,
+}
+
+// Clockwise rotation applied to the outgoing frame
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+// Resampling kernel used when scaling frames
+#[derive(Clone, Copy, PartialEq)]
+pub enum Resampling {
+    Nearest,
+    Bilinear,
+    Lanczos,
+}
+
+// How a masked region is hidden
+#[derive(Clone, Copy, PartialEq)]
+pub enum MaskMode {
+    Solid([u8; 4]), // RGBA fill
+    Blur(f32),      // Gaussian blur sigma in pixels
+    Pixelate(u32),  // Block size in pixels
+}
+
+// Rectangle to hide, in fractions (0.0-1.0) of the frame size
+#[derive(Clone, PartialEq)]
+pub struct MaskRegion {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub mode: MaskMode,
 }
 
 impl ScreenCapture {
     // Constructor that initializes the capture thread and returns the receiver
-    pub fn new(index: usize) -> Result<Self, String> {
-        let (tx, rx) = watch::channel(Frame {
-            data: vec![],
-            width: 0,
-            height: 0,
-        });
+    pub fn new(index: usize, fps: u32) -> Result<Self, String> {
+        Self::with_displays(vec![index], fps)
+    }
 
-        thread::spawn(move || {
-            // Create a Capturer to capture the screen
-            let mut displays = Display::all().unwrap();
-            let display = displays.remove(index);
-            let mut capturer = Capturer::new(display).unwrap();
-            let width = capturer.width() as u32;
-            let height = capturer.height() as u32;
-
-            // Start capturing frames in a loop
-            let capture_interval = Duration::from_millis(30);
-            loop {
-                match capturer.frame() {
-                    Ok(frame) => {
-                        let rgba_frame = convert_bgra_to_rgba(&frame, width, height);
-                        let frame_data = Frame {
-                            data: rgba_frame.to_vec(),
-                            width,
-                            height,
-                        };
+    // Capture several displays and stitch them into one frame following their layout
+    pub fn with_displays(mut indices: Vec<usize>, fps: u32) -> Result<Self, String> {
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() {
+            return Err("No display selected".to_string());
+        }
+        let (tx, rx) = watch::channel(Frame::empty());
+        let fps = Arc::new(AtomicU32::new(fps));
+        let target_fps = Arc::clone(&fps);
+        let (cursor, cursor_options) = watch::channel(CursorOptions::new());
+        let (state_tx, state) = watch::channel(CaptureState::Starting);
+        let running = Arc::new(AtomicBool::new(true));
+        let keep_running = Arc::clone(&running);
 
-                        if tx.send(frame_data).is_err() {
-                            eprintln!("Receiver has been dropped, stopping capture.");
-                            break;
-                        }
-                    }
-                    Err(error) => {
-                        if error.kind() != std::io::ErrorKind::WouldBlock {
-                            eprintln!("Error capturing frame: {:?}", error);
-                            break;
-                        }
+        let thread = thread::spawn(move || {
+            // scrap frames don't include the pointer, draw it ourselves where possible
+            #[cfg(target_os = "linux")]
+            let mut cursor_tracker = match crate::x11::CursorTracker::new(None) {
+                Ok(tracker) => Some(tracker),
+                Err(e) => {
+                    eprintln!("Pointer will not be captured: {}", e);
+                    None
+                }
+            };
+            #[cfg(not(target_os = "linux"))]
+            let _ = cursor_options; // No pointer source on this platform yet
+
+            let mut attempt = 0;
+            let mut slate_size = (1280, 720);
+            let mut recycler = FrameRecycler::default();
+            while keep_running.load(Ordering::Relaxed) {
+                let reason = match Stitcher::open(&indices) {
+                    Ok(mut stitcher) => {
+                        let _ = state_tx.send(CaptureState::Running);
+                        slate_size = (stitcher.width, stitcher.height);
+
+                        // Start capturing frames in a loop
+                        let mut pacer = Pacer::new(target_fps.load(Ordering::Relaxed));
+                        let mut last_check = Instant::now();
+                        let reason = loop {
+                            if !keep_running.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            pacer.set_fps(target_fps.load(Ordering::Relaxed));
+
+                            // A mode switch doesn't always surface as an error, look for it once a second
+                            if last_check.elapsed() >= Duration::from_secs(1) {
+                                last_check = Instant::now();
+                                if stitcher.resolution_changed() {
+                                    break "Display resolution changed".to_string();
+                                }
+                            }
+
+                            match stitcher.grab() {
+                                Ok(true) => {
+                                    attempt = 0;
+                                    // The recycled frame still shows the pointer where it was drawn last
+                                    #[cfg(target_os = "linux")]
+                                    let pointer = cursor_tracker
+                                        .as_ref()
+                                        .and_then(|tracker| tracker.drawn_area())
+                                        .and_then(|area| clip_area(area, stitcher.width, stitcher.height));
+                                    #[cfg(not(target_os = "linux"))]
+                                    let pointer = None;
+                                    #[allow(unused_mut)]
+                                    let mut frame_data = stitcher.frame(&mut recycler, pointer);
+
+                                    #[cfg(target_os = "linux")]
+                                    if let Some(tracker) = &mut cursor_tracker {
+                                        let options = *cursor_options.borrow();
+                                        let origin = (stitcher.left, stitcher.top);
+                                        if let Err(e) = tracker.composite(&mut frame_data, origin, &options) {
+                                            eprintln!("Error drawing pointer: {}", e);
+                                        }
+                                    }
+
+                                    // Receivers clone what they borrow, so the frame handed back is free
+                                    recycler.recycle(tx.send_replace(frame_data));
+                                    if tx.is_closed() {
+                                        eprintln!("Receiver has been dropped, stopping capture.");
+                                        return;
+                                    }
+                                }
+                                Ok(false) => {}
+                                Err(reason) => break reason,
+                            }
+
+                            // Wait for the next capture deadline (to control FPS)
+                            pacer.wait();
+                        };
+                        reason
                     }
+                    Err(reason) => reason,
+                };
+
+                // Keep receivers fed with a slate while the capture is down
+                attempt += 1;
+                recycler = FrameRecycler::default();
+                if attempt > MAX_CAPTURE_RETRIES {
+                    eprintln!("Giving up on screen capture: {}", reason);
+                    let _ = tx.send(message_slate(slate_size, "Screen capture stopped"));
+                    let _ = state_tx.send(CaptureState::Failed(reason));
+                    return;
                 }
+                eprintln!("Screen capture interrupted ({}), retry {} of {}", reason, attempt, MAX_CAPTURE_RETRIES);
+                if tx.send(message_slate(slate_size, "Reconnecting...")).is_err() {
+                    return;
+                }
+                let _ = state_tx.send(CaptureState::Restarting { attempt, reason });
 
-                // Sleep for the capture interval (to control FPS)
-                thread::sleep(capture_interval);
+                wait_before_retry(attempt, &keep_running);
             }
         });
 
-        Ok(ScreenCapture { rx })
+        Ok(ScreenCapture { rx, fps, cursor, state, running, thread: Some(thread) })
+    }
+
+}
+
+// Exponential backoff between capture restarts, still reacting quickly to a stop request
+pub fn wait_before_retry(attempt: u32, running: &AtomicBool) {
+    let delay = (FIRST_RETRY_DELAY * 2u32.pow(attempt.saturating_sub(1).min(16))).min(MAX_RETRY_DELAY);
+    let resume = Instant::now() + delay;
+    while Instant::now() < resume && running.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Capturers of the selected displays and the canvas they are stitched into
+struct Stitcher {
+    capturers: Vec<Capturer>,
+    indices: Vec<usize>,
+    sizes: Vec<(u32, u32)>,
+    origins: Vec<(i32, i32)>,
+    left: i32,
+    top: i32,
+    width: u32,
+    height: u32,
+    canvas: Vec<u8>, // BGRA as captured, no channel swap on this thread
+    stride: usize,
+    captured: Vec<bool>, // Displays that delivered at least one frame
+    updated: Vec<bool>, // Displays copied into the canvas since the last frame
+}
+
+impl Stitcher {
+    fn open(indices: &[usize]) -> Result<Self, String> {
+        let mut displays: Vec<Option<Display>> = Display::all()
+            .map_err(|e| format!("Cannot list displays: {}", e))?
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut capturers = Vec::new();
+        for &index in indices {
+            let display = displays
+                .get_mut(index)
+                .and_then(Option::take)
+                .ok_or_else(|| format!("Monitor {} is not connected", index + 1))?;
+            let capturer = Capturer::new(display)
+                .map_err(|e| format!("Cannot capture monitor {}: {}", index + 1, e))?;
+            capturers.push(capturer);
+        }
+        let sizes: Vec<(u32, u32)> = capturers
+            .iter()
+            .map(|capturer| (capturer.width() as u32, capturer.height() as u32))
+            .collect();
+        let origins = display_origins(indices, &sizes);
+
+        // Bounding box of all selected displays, gaps between them stay black
+        let left = origins.iter().map(|origin| origin.0).min().unwrap_or(0);
+        let top = origins.iter().map(|origin| origin.1).min().unwrap_or(0);
+        let right = origins.iter().zip(&sizes).map(|(origin, size)| origin.0 + size.0 as i32).max().unwrap_or(0);
+        let bottom = origins.iter().zip(&sizes).map(|(origin, size)| origin.1 + size.1 as i32).max().unwrap_or(0);
+        let width = (right - left) as u32;
+        let height = (bottom - top) as u32;
+        let canvas = vec![0u8; (width * height * 4) as usize];
+
+        Ok(Self {
+            captured: vec![false; capturers.len()],
+            updated: vec![false; capturers.len()],
+            capturers,
+            indices: indices.to_vec(),
+            sizes,
+            origins,
+            left,
+            top,
+            width,
+            height,
+            canvas,
+            stride: width as usize * 4,
+        })
+    }
+
+    // Copy new frames into the canvas. True once every display delivered and one of them changed.
+    fn grab(&mut self) -> Result<bool, String> {
+        let mut updated = false;
+        let single = self.capturers.len() == 1;
+        for (index, capturer) in self.capturers.iter_mut().enumerate() {
+            match capturer.frame() {
+                Ok(frame) => {
+                    let (display_width, display_height) = self.sizes[index];
+                    if frame.len() < (display_width * display_height * 4) as usize {
+                        return Err("Display resolution changed".to_string());
+                    }
+                    // A lone display keeps the row padding of the captured frame, so it is one copy
+                    let native_stride = frame.len() / display_height as usize;
+                    if single && self.stride != native_stride {
+                        self.stride = native_stride;
+                        self.canvas = vec![0; native_stride * display_height as usize];
+                    }
+                    let x = (self.origins[index].0 - self.left) as usize;
+                    let y = (self.origins[index].1 - self.top) as usize;
+                    copy_rows(
+                        &frame,
+                        native_stride,
+                        &mut self.canvas[y * self.stride + x * 4..],
+                        self.stride,
+                        display_width as usize * 4,
+                        display_height as usize,
+                    );
+                    self.captured[index] = true;
+                    self.updated[index] = true;
+                    updated = true;
+                }
+                Err(error) => {
+                    if error.kind() != std::io::ErrorKind::WouldBlock {
+                        return Err(format!("Error capturing frame: {}", error));
+                    }
+                }
+            }
+        }
+        Ok(updated && self.captured.iter().all(|&done| done))
+    }
+
+    // Hand the canvas over as a frame; `pointer` is where the recycled frame has the pointer drawn
+    fn frame(&mut self, recycler: &mut FrameRecycler, pointer: Option<DamageRect>) -> Frame {
+        let data = if self.capturers.len() == 1 {
+            // The next grab rewrites the whole canvas, so it can go as it is
+            let len = self.canvas.len();
+            std::mem::replace(&mut self.canvas, recycler.take(len))
+        } else {
+            let mut changed: Vec<DamageRect> = self
+                .updated
+                .iter()
+                .enumerate()
+                .filter(|(_, &updated)| updated)
+                .map(|(index, _)| DamageRect {
+                    x: (self.origins[index].0 - self.left) as u32,
+                    y: (self.origins[index].1 - self.top) as u32,
+                    width: self.sizes[index].0,
+                    height: self.sizes[index].1,
+                })
+                .collect();
+            changed.extend(pointer);
+            recycler.copy(&self.canvas, self.stride, Some(&changed))
+        };
+        self.updated.fill(false);
+        Frame::with_stride(data, self.width, self.height, PixelFormat::Bgra, self.stride)
+    }
+
+    // Whether a captured display was unplugged or switched to another mode
+    fn resolution_changed(&self) -> bool {
+        let Ok(displays) = Display::all() else {
+            return true;
+        };
+        self.indices.iter().zip(&self.sizes).any(|(&index, &(width, height))| {
+            displays
+                .get(index)
+                .is_none_or(|display| display.width() as u32 != width || display.height() as u32 != height)
+        })
+    }
+}
+
+// Plain frame carrying a status message, sent to receivers while no picture is available
+pub fn message_slate((width, height): (u32, u32), message: &str) -> Frame {
+    let mut frame = Frame::new(vec![0; (width * height * 4) as usize], width, height, PixelFormat::Rgba);
+    let settings = BlankSettings {
+        kind: BlankKind::Message,
+        color: [40, 40, 40, 255],
+        message: message.to_string(),
+        text_color: [255, 255, 255, 255],
+        ..BlankSettings::new()
+    };
+    blank(&mut frame, &settings, None);
+    frame
+}
+
+// Top-left corner of each display on the virtual desktop. X11 reports the real layout,
+// elsewhere displays are assumed to sit side by side in index order.
+#[allow(unused_variables)]
+fn display_origins(indices: &[usize], sizes: &[(u32, u32)]) -> Vec<(i32, i32)> {
+    #[cfg(target_os = "linux")]
+    if let Ok(rects) = crate::x11::monitor_rects() {
+        let origins: Option<Vec<(i32, i32)>> = indices
+            .iter()
+            .map(|&index| rects.get(index).map(|rect| (rect.x, rect.y)))
+            .collect();
+        if let Some(origins) = origins {
+            return origins;
+        }
+    }
+    let mut x = 0;
+    sizes
+        .iter()
+        .map(|&(width, _)| {
+            let origin = (x, 0);
+            x += width as i32;
+            origin
+        })
+        .collect()
+}
+
+// Stop the capture thread and wait for it, so the displays are released before the next source starts
+impl Drop for ScreenCapture {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("Capture thread panicked.");
+            }
+        }
     }
+}
 
-    pub fn receive_frame(&mut self) -> Option<Frame> {
-        let frame = self.rx.borrow();
+impl FrameSource for ScreenCapture {
+    fn receive_frame(&mut self) -> Option<Frame> {
+        if !self.rx.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let frame = self.rx.borrow_and_update();
         if !frame.data.is_empty() {
             Some(frame.clone())
         } else {
             None
         }
     }
+
+    fn set_fps(&mut self, fps: u32) {
+        self.fps.store(fps, Ordering::Relaxed);
+    }
+
+    fn set_cursor(&mut self, options: CursorOptions) {
+        let _ = self.cursor.send(options);
+    }
+
+    fn state(&self) -> Option<watch::Receiver<CaptureState>> {
+        Some(self.state.clone())
+    }
 }
 
 impl CropValues {
     pub fn new(left: f32, right: f32, top: f32, bottom: f32) -> Self {
-        Self { left, right, top, bottom }
+        Self { left, right, top, bottom, mode: CropMode::Trim }
+    }
+
+    // Percentages must be in 0..=100 and opposite sides must not overlap
+    pub fn validate(&self) -> Result<(), String> {
+        for value in [self.left, self.right, self.top, self.bottom] {
+            if !(0.0..=100.0).contains(&value) {
+                return Err(format!("Crop value {} is outside 0-100%", value));
+            }
+        }
+        if self.left + self.right > 100.0 {
+            return Err("Left and right crop exceed 100%".to_string());
+        }
+        if self.top + self.bottom > 100.0 {
+            return Err("Top and bottom crop exceed 100%".to_string());
+        }
+        Ok(())
     }
 }
 
-pub fn crop(frame: &mut Frame, crop: CropValues) {
+pub fn crop(frame: &mut Frame, crop: &CropValues) {
     let channels = 4; // Assuming RGBA format (4 bytes per pixel)
     let width = frame.width as usize;
     let height = frame.height as usize;
+    // Nothing to cut from an empty or truncated frame
+    if width == 0 || height == 0 || frame.stride < width * channels || frame.data.len() < frame.stride * height {
+        return;
+    }
 
-    // Calculate the pixel bounds for each side based on percentages
-    let left_bound = ((crop.left / 100.0) * width as f32).round() as usize;
-    let right_bound = ((crop.right / 100.0) * width as f32).round() as usize;
-    let top_bound = ((crop.top / 100.0) * height as f32).round() as usize;
-    let bottom_bound = ((crop.bottom / 100.0) * height as f32).round() as usize;
+    // Calculate the pixel bounds for each side based on percentages,
+    // never letting opposite sides overlap
+    let left_bound = (((crop.left / 100.0) * width as f32).round() as usize).min(width);
+    let right_bound = (((crop.right / 100.0) * width as f32).round() as usize).min(width.saturating_sub(left_bound));
+    let top_bound = (((crop.top / 100.0) * height as f32).round() as usize).min(height);
+    let bottom_bound = (((crop.bottom / 100.0) * height as f32).round() as usize).min(height.saturating_sub(top_bound));
 
-    // Modify the data field of the Frame in-place
-    for y in 0..height {
-        for x in 0..left_bound {
-            let index = (y * width + x) * channels;
-            frame.data[index..index + channels].copy_from_slice(&[255, 255, 255, 255]);
+    if left_bound + right_bound == 0 && top_bound + bottom_bound == 0 {
+        return;
+    }
+
+    match crop.mode {
+        CropMode::Trim => {
+            // Keep at least one pixel so the frame stays displayable
+            let new_width = width.saturating_sub(left_bound + right_bound).max(1);
+            let new_height = height.saturating_sub(top_bound + bottom_bound).max(1);
+            let left_bound = left_bound.min(width.saturating_sub(new_width));
+            let top_bound = top_bound.min(height.saturating_sub(new_height));
+
+            let mut data = Vec::with_capacity(new_width * new_height * channels);
+            for y in top_bound..top_bound + new_height {
+                let start = y * frame.stride + left_bound * channels;
+                data.extend_from_slice(&frame.data[start..start + new_width * channels]);
+            }
+            frame.data = data;
+            frame.width = new_width as u32;
+            frame.height = new_height as u32;
+            frame.stride = new_width * channels;
+        }
+        CropMode::Fill(color) => {
+            // Modify the data field of the Frame in-place
+            for y in 0..height {
+                let row = &mut frame.data[y * frame.stride..y * frame.stride + width * channels];
+                let full_row = y < top_bound || y >= height.saturating_sub(bottom_bound);
+                for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                    if full_row || x < left_bound || x >= width.saturating_sub(right_bound) {
+                        pixel.copy_from_slice(&color);
+                    }
+                }
+            }
         }
     }
+}
 
-    for y in 0..height {
-        for x in (width - right_bound)..width {
-            let index = (y * width + x) * channels;
-            frame.data[index..index + channels].copy_from_slice(&[255, 255, 255, 255]);
+// What the receivers see while the caster is blanked
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlankKind {
+    Color,   // Plain background colour
+    Slate,   // Image centred on the background colour
+    Message, // Text centred on the background colour
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlankSettings {
+    pub kind: BlankKind,
+    pub color: [u8; 4],
+    pub slate_path: String,
+    pub message: String,
+    pub text_color: [u8; 4],
+}
+
+impl BlankSettings {
+    pub fn new() -> Self {
+        Self {
+            kind: BlankKind::Color,
+            color: [255, 255, 255, 255],
+            slate_path: String::new(),
+            message: "Be right back".to_string(),
+            text_color: [0, 0, 0, 255],
         }
     }
+}
 
-    for y in 0..top_bound {
-        for x in 0..width {
-            let index = (y * width + x) * channels;
-            frame.data[index..index + channels].copy_from_slice(&[255, 255, 255, 255]);
+// Replace the frame content, keeping its size. `slate` must already fit inside the frame.
+pub fn blank(frame: &mut Frame, settings: &BlankSettings, slate: Option<&RgbaImage>) {
+    // Assuming the frame is in RGBA format (4 bytes per pixel)
+    let [r, g, b, _] = settings.color;
+    for chunk in frame.data.chunks_exact_mut(4) {
+        chunk.copy_from_slice(&[r, g, b, 255]);
+    }
+
+    match settings.kind {
+        BlankKind::Color => {}
+        BlankKind::Slate => {
+            let Some(slate) = slate else {
+                return;
+            };
+            if slate.width() > frame.width || slate.height() > frame.height {
+                return;
+            }
+            let x = (frame.width - slate.width()) / 2;
+            let y = (frame.height - slate.height()) / 2;
+            blend_image(frame, slate, x as i32, y as i32, 1.0);
+        }
+        BlankKind::Message => {
+            let lines: Vec<&str> = settings.message.lines().collect();
+            let size = (frame.height as f32 / 12.0).max(12.0);
+            let line_height = measure_text("", size).1 as i32;
+            let mut y = (frame.height as i32 - line_height * lines.len() as i32) / 2;
+            for line in lines {
+                let (line_width, _) = measure_text(line, size);
+                let x = (frame.width as i32 - line_width as i32) / 2;
+                draw_text(frame, line, x, y, size, settings.text_color);
+                y += line_height;
+            }
+        }
+    }
+}
+
+// Alpha-blend an RGBA image into an RGBA or BGRA frame with its top-left corner at (x, y)
+pub fn blend_image(frame: &mut Frame, image: &RgbaImage, x: i32, y: i32, opacity: f32) {
+    if frame.format.bytes_per_pixel() != Some(4) {
+        return;
+    }
+    let width = frame.width as i32;
+    let height = frame.height as i32;
+    let opacity = opacity.clamp(0.0, 1.0);
+    for (ix, iy, pixel) in image.enumerate_pixels() {
+        let px = x + ix as i32;
+        let py = y + iy as i32;
+        if px < 0 || py < 0 || px >= width || py >= height {
+            continue;
+        }
+        let alpha = pixel[3] as f32 / 255.0 * opacity;
+        let index = py as usize * frame.stride + px as usize * 4;
+        let color = native_color(frame.format, pixel.0);
+        for (dst, src) in frame.data[index..index + 3].iter_mut().zip(&color[..3]) {
+            *dst = (*dst as f32 + (*src as f32 - *dst as f32) * alpha).round() as u8;
+        }
+    }
+}
+
+// `color` in the byte order of a 4-byte-per-pixel frame
+fn native_color(format: PixelFormat, [r, g, b, a]: [u8; 4]) -> [u8; 4] {
+    match format {
+        PixelFormat::Bgra => [b, g, r, a],
+        _ => [r, g, b, a],
+    }
+}
+
+// Corner of the frame an overlay is anchored to
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+// Top-left position of a width x height box placed in a corner, `margin` pixels from the edges
+pub fn corner_position(frame: &Frame, corner: Corner, width: u32, height: u32, margin: u32) -> (i32, i32) {
+    let right = frame.width as i32 - width as i32 - margin as i32;
+    let bottom = frame.height as i32 - height as i32 - margin as i32;
+    match corner {
+        Corner::TopLeft => (margin as i32, margin as i32),
+        Corner::TopRight => (right, margin as i32),
+        Corner::BottomLeft => (margin as i32, bottom),
+        Corner::BottomRight => (right, bottom),
+    }
+}
+
+// Fill a rectangle blending `color` by its alpha, clipped to the frame
+fn shade_rect(frame: &mut Frame, x: i32, y: i32, width: u32, height: u32, color: [u8; 4]) {
+    let alpha = color[3] as f32 / 255.0;
+    let x0 = x.max(0) as u32;
+    let y0 = y.max(0) as u32;
+    let x1 = ((x + width as i32).max(0) as u32).min(frame.width);
+    let y1 = ((y + height as i32).max(0) as u32).min(frame.height);
+    for py in y0..y1 {
+        for px in x0..x1 {
+            let index = ((py * frame.width + px) * 4) as usize;
+            for (dst, src) in frame.data[index..index + 3].iter_mut().zip(&color[..3]) {
+                *dst = (*dst as f32 + (*src as f32 - *dst as f32) * alpha).round() as u8;
+            }
+        }
+    }
+}
+
+// Full-width band with centred text along the top or bottom edge
+pub fn banner(frame: &mut Frame, text: &str, at_top: bool, background: [u8; 4], text_color: [u8; 4]) {
+    let size = (frame.height as f32 / 24.0).max(10.0);
+    let (text_width, text_height) = measure_text(text, size);
+    let band_height = text_height + text_height / 2;
+    let y = if at_top { 0 } else { frame.height as i32 - band_height as i32 };
+    shade_rect(frame, 0, y, frame.width, band_height, background);
+    let x = (frame.width as i32 - text_width as i32) / 2;
+    draw_text(frame, text, x, y + (text_height / 4) as i32, size, text_color);
+}
+
+// Text on a translucent dark box anchored to a corner
+pub fn corner_label(frame: &mut Frame, text: &str, corner: Corner, text_color: [u8; 4]) {
+    let size = (frame.height as f32 / 30.0).max(10.0);
+    let (text_width, text_height) = measure_text(text, size);
+    let padding = text_height / 4;
+    let margin = frame.height / 40;
+    let (x, y) = corner_position(frame, corner, text_width + 2 * padding, text_height, margin);
+    shade_rect(frame, x, y, text_width + 2 * padding, text_height, [0, 0, 0, 160]);
+    draw_text(frame, text, x + padding as i32, y, size, text_color);
+}
+
+// Antialiased polyline `thickness` pixels wide, blended with the colour's alpha.
+// A single point draws a dot. Works on RGBA and BGRA frames.
+pub fn draw_polyline(frame: &mut Frame, points: &[(f32, f32)], thickness: f32, color: [u8; 4]) {
+    let Some(&first) = points.first() else {
+        return;
+    };
+    if frame.format.bytes_per_pixel() != Some(4) {
+        return;
+    }
+    let radius = (thickness / 2.0).max(0.5);
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (first.0, first.1, first.0, first.1);
+    for &(x, y) in points {
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+    let clamp_x = |value: f32| (value.max(0.0) as usize).min(frame.width as usize);
+    let clamp_y = |value: f32| (value.max(0.0) as usize).min(frame.height as usize);
+    let (x0, x1) = (clamp_x(min_x - radius - 1.0), clamp_x(max_x + radius + 2.0));
+    let (y0, y1) = (clamp_y(min_y - radius - 1.0), clamp_y(max_y + radius + 2.0));
+    if x0 >= x1 || y0 >= y1 {
+        return;
+    }
+
+    // Coverage of the whole line, so overlapping segments don't blend twice
+    let box_width = x1 - x0;
+    let mut coverage = vec![0.0f32; box_width * (y1 - y0)];
+    let segments: Vec<((f32, f32), (f32, f32))> = if points.len() == 1 {
+        vec![(first, first)]
+    } else {
+        points.windows(2).map(|pair| (pair[0], pair[1])).collect()
+    };
+    for ((ax, ay), (bx, by)) in segments {
+        let sx0 = clamp_x(ax.min(bx) - radius - 1.0).max(x0);
+        let sx1 = clamp_x(ax.max(bx) + radius + 2.0).min(x1);
+        let sy0 = clamp_y(ay.min(by) - radius - 1.0).max(y0);
+        let sy1 = clamp_y(ay.max(by) + radius + 2.0).min(y1);
+        let (dx, dy) = (bx - ax, by - ay);
+        let length_squared = dx * dx + dy * dy;
+        for py in sy0..sy1 {
+            for px in sx0..sx1 {
+                // Distance from the pixel centre to the segment
+                let (cx, cy) = (px as f32 + 0.5, py as f32 + 0.5);
+                let t = if length_squared > 0.0 {
+                    (((cx - ax) * dx + (cy - ay) * dy) / length_squared).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                let distance = ((cx - ax - t * dx).powi(2) + (cy - ay - t * dy).powi(2)).sqrt();
+                let value = (radius + 0.5 - distance).clamp(0.0, 1.0);
+                let cell = &mut coverage[(py - y0) * box_width + (px - x0)];
+                *cell = cell.max(value);
+            }
+        }
+    }
+
+    let color = native_color(frame.format, color);
+    let opacity = color[3] as f32 / 255.0;
+    for (row, py) in coverage.chunks_exact(box_width).zip(y0..y1) {
+        for (value, px) in row.iter().zip(x0..x1) {
+            if *value <= 0.0 {
+                continue;
+            }
+            let alpha = value * opacity;
+            let index = py * frame.stride + px * 4;
+            for (dst, src) in frame.data[index..index + 3].iter_mut().zip(&color[..3]) {
+                *dst = (*dst as f32 + (*src as f32 - *dst as f32) * alpha).round() as u8;
+            }
         }
     }
+}
+
+pub fn rotate(frame: &mut Frame, rotation: Rotation) {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let mut data = vec![0u8; frame.data.len()];
 
-    for y in (height - bottom_bound)..height {
+    for y in 0..height {
         for x in 0..width {
-            let index = (y * width + x) * channels;
-            frame.data[index..index + channels].copy_from_slice(&[255, 255, 255, 255]);
+            // Destination coordinates and row length after rotating (x, y)
+            let (dx, dy, row) = match rotation {
+                Rotation::Deg90 => (height - 1 - y, x, height),
+                Rotation::Deg180 => (width - 1 - x, height - 1 - y, width),
+                Rotation::Deg270 => (y, width - 1 - x, height),
+            };
+            let src = (y * width + x) * 4;
+            let dst = (dy * row + dx) * 4;
+            data[dst..dst + 4].copy_from_slice(&frame.data[src..src + 4]);
         }
     }
+
+    frame.data = data;
+    if rotation != Rotation::Deg180 {
+        std::mem::swap(&mut frame.width, &mut frame.height);
+        frame.stride = frame.width as usize * 4;
+    }
 }
 
-pub fn blank(frame: &mut Frame, is_blank: bool) {
-    // Assuming the frame is in RGBA format (4 bytes per pixel)
-    if is_blank {
-        for chunk in frame.data.chunks_exact_mut(4) {
-            chunk.copy_from_slice(&[255, 255, 255, 255]); // Fill with white (RGBA)
+// Downscale the frame to fit within max_width x max_height, preserving its aspect ratio.
+// Frames that already fit are left untouched.
+pub fn scale(frame: &mut Frame, max_width: u32, max_height: u32, resampling: Resampling) {
+    if frame.width <= max_width && frame.height <= max_height {
+        return;
+    }
+    if frame.data.len() != (frame.width * frame.height * 4) as usize {
+        eprintln!("Frame data does not match its size, skipping scale");
+        return;
+    }
+    let factor = f64::min(
+        max_width as f64 / frame.width as f64,
+        max_height as f64 / frame.height as f64,
+    );
+    let width = ((frame.width as f64 * factor).round() as u32).max(1);
+    let height = ((frame.height as f64 * factor).round() as u32).max(1);
+
+    let filter = match resampling {
+        Resampling::Nearest => FilterType::Nearest,
+        Resampling::Bilinear => FilterType::Triangle,
+        Resampling::Lanczos => FilterType::Lanczos3,
+    };
+    let data = std::mem::take(&mut frame.data);
+    let Some(image) = RgbaImage::from_raw(frame.width, frame.height, data) else {
+        return;
+    };
+    let scaled = imageops::resize(&image, width, height, filter);
+    frame.width = width;
+    frame.height = height;
+    frame.stride = width as usize * 4;
+    frame.data = scaled.into_raw();
+}
+
+pub fn mask(frame: &mut Frame, region: &MaskRegion) -> Result<(), String> {
+    if frame.format != PixelFormat::Rgba || !frame.is_packed() {
+        return Err(format!("Masking needs packed RGBA, got {:?} with stride {}", frame.format, frame.stride));
+    }
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+
+    // Pixel bounds of the region, clamped to the frame
+    let to_pixels = |value: f32, size: usize| ((value * size as f32).round().max(0.0) as usize).min(size);
+    let x0 = to_pixels(region.x, width);
+    let x1 = to_pixels(region.x + region.width, width);
+    let y0 = to_pixels(region.y, height);
+    let y1 = to_pixels(region.y + region.height, height);
+    if x0 >= x1 || y0 >= y1 {
+        return Ok(());
+    }
+
+    match region.mode {
+        MaskMode::Solid(color) => {
+            for y in y0..y1 {
+                for pixel in frame.data[(y * width + x0) * 4..(y * width + x1) * 4].chunks_exact_mut(4) {
+                    pixel.copy_from_slice(&color);
+                }
+            }
+        }
+        MaskMode::Pixelate(block) => {
+            let block = block.max(1) as usize;
+            for by in (y0..y1).step_by(block) {
+                for bx in (x0..x1).step_by(block) {
+                    let (bx1, by1) = ((bx + block).min(x1), (by + block).min(y1));
+
+                    // Average colour of the block, then paint it back
+                    let mut sum = [0usize; 4];
+                    for y in by..by1 {
+                        for pixel in frame.data[(y * width + bx) * 4..(y * width + bx1) * 4].chunks_exact(4) {
+                            for (total, value) in sum.iter_mut().zip(pixel) {
+                                *total += *value as usize;
+                            }
+                        }
+                    }
+                    let count = (bx1 - bx) * (by1 - by);
+                    let average = sum.map(|total| (total / count) as u8);
+                    for y in by..by1 {
+                        for pixel in frame.data[(y * width + bx) * 4..(y * width + bx1) * 4].chunks_exact_mut(4) {
+                            pixel.copy_from_slice(&average);
+                        }
+                    }
+                }
+            }
+        }
+        MaskMode::Blur(sigma) => {
+            let row_bytes = (x1 - x0) * 4;
+            let mut region_data = Vec::with_capacity(row_bytes * (y1 - y0));
+            for y in y0..y1 {
+                region_data.extend_from_slice(&frame.data[(y * width + x0) * 4..(y * width + x1) * 4]);
+            }
+            let region_image = RgbaImage::from_raw((x1 - x0) as u32, (y1 - y0) as u32, region_data)
+                .ok_or("Mask region does not match the frame")?;
+            let blurred = imageops::blur(&region_image, sigma.max(0.1));
+            for (row, y) in blurred.as_raw().chunks_exact(row_bytes).zip(y0..y1) {
+                frame.data[(y * width + x0) * 4..(y * width + x1) * 4].copy_from_slice(row);
+            }
         }
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canvas(width: u32, height: u32, value: u8) -> Vec<u8> {
+        vec![value; (width * height * 4) as usize]
+    }
+
+    #[test]
+    fn recycled_frame_only_takes_the_changed_areas() {
+        let mut recycler = FrameRecycler::default();
+        let mut master = canvas(8, 4, 1);
+        let first = recycler.copy(&master, 32, Some(&[]));
+        assert_eq!(first, master);
+
+        // Only the changed rect is copied into the handed back buffer
+        master.fill(2);
+        recycler.recycle(Frame::new(first, 8, 4, PixelFormat::Bgra));
+        let second = recycler.copy(&master, 32, Some(&[DamageRect { x: 2, y: 1, width: 3, height: 2 }]));
+        for (index, &value) in second.iter().enumerate() {
+            let (x, y) = ((index % 32) / 4, index / 32);
+            let inside = (2..5).contains(&x) && (1..3).contains(&y);
+            assert_eq!(value, if inside { 2 } else { 1 }, "pixel {},{}", x, y);
+        }
+
+        // Anything unknown is a full copy
+        recycler.recycle(Frame::new(second, 8, 4, PixelFormat::Bgra));
+        assert_eq!(recycler.copy(&master, 32, None), master);
+    }
+
+    #[test]
+    fn slates_and_other_sizes_are_not_reused() {
+        let mut recycler = FrameRecycler::default();
+        let master = canvas(8, 4, 3);
+        recycler.recycle(message_slate((8, 4), ""));
+        assert_eq!(recycler.copy(&master, 32, Some(&[])), master);
+
+        recycler.recycle(Frame::new(canvas(4, 4, 0), 4, 4, PixelFormat::Bgra));
+        assert_eq!(recycler.copy(&master, 32, Some(&[])), master);
+        assert_eq!(recycler.take(16).len(), 16);
+    }
+
+    #[test]
+    fn clips_areas_to_the_frame() {
+        assert_eq!(clip_area((-5, -5, 10, 20), 8, 16), Some(DamageRect { x: 0, y: 0, width: 8, height: 16 }));
+        assert_eq!(clip_area((2, 3, 4, 5), 8, 16), Some(DamageRect { x: 2, y: 3, width: 2, height: 2 }));
+        assert_eq!(clip_area((9, 0, 12, 4), 8, 16), None);
+    }
+}
@@ -1,23 +1,54 @@
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::{broadcast, Mutex};
-use tokio::io::{AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::runtime::Runtime;
 use std::sync::{Arc, atomic::{AtomicUsize,AtomicBool,Ordering}};
 use std::net::SocketAddr;
 use std::collections::{HashMap};
 use bytes::{Bytes};
 use std::time::{Instant,Duration};
-use crate::screen::Frame;
-use bincode;
+use crate::screen::{DamageRect, Frame};
+use crate::compression::{Codec, CompressionSettings, HELLO_LEN, accepted_codecs, compress};
+use crate::delta::{DeltaEncoder, KEYFRAME_REQUEST};
+
+// Frames that show nothing new are only re-sent this often, so receivers know we're alive
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+// Receivers that haven't introduced themselves by then are dropped
+const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A frame ready to write, as is and compressed for the receivers that can decode it
+#[derive(Clone)]
+struct Message {
+    plain: Bytes,
+    compressed: Option<(Codec, Bytes)>,
+}
+
+// Compression work since the statistics were last taken
+#[derive(Default)]
+struct CompressionTotals {
+    frames: u32,
+    raw_bytes: u64,
+    compressed_bytes: u64,
+    time: Duration,
+}
 
 // Define a struct to manage the server state
 pub struct StreamServer {
-    sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>, // Updated type
-    sender: broadcast::Sender<Bytes>,                               // Broadcast channel
+    sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>>>, // Sending side of each client
+    sender: broadcast::Sender<Message>,                             // Broadcast channel
     runtime: Arc<Runtime>,
     client_count: Arc<AtomicUsize>,
-    time: Instant,
+    next_send: Instant, // Deadline of the next broadcast
+    interval: Duration,
     priority: AtomicBool,
+    last_broadcast: Instant,
+    // What changed since the last broadcast, including frames that were skipped; None if unknown
+    unsent_damage: Option<Vec<DamageRect>>,
+    keyframe_requested: Arc<AtomicBool>, // Set when a receiver joins or loses sync, so it gets a keyframe right away
+    encoder: DeltaEncoder,
+    compression: CompressionSettings,
+    totals: CompressionTotals,
 }
 
 impl StreamServer {
@@ -28,14 +59,22 @@ impl StreamServer {
         let (sender, _) = broadcast::channel(2048); // Buffer size of 256 messages
         let sockets = Arc::new(Mutex::new(HashMap::new()));
         let client_count = Arc::new(AtomicUsize::new(0));
+        let keyframe_requested = Arc::new(AtomicBool::new(false));
 
         let server = Self {
             sockets: Arc::clone(&sockets),
             sender: sender.clone(),
             runtime: Arc::clone(&runtime),
             client_count: Arc::clone(&client_count),
-            time: Instant::now(),
+            next_send: Instant::now(),
+            interval: Duration::from_secs_f64(1.0 / 30.0),
             priority: AtomicBool::new(false),
+            last_broadcast: Instant::now(),
+            unsent_damage: None,
+            keyframe_requested: Arc::clone(&keyframe_requested),
+            encoder: DeltaEncoder::new(),
+            compression: CompressionSettings::new(),
+            totals: CompressionTotals::default(),
         };
 
         // Use the runtime to spawn a task that starts the server
@@ -55,19 +94,22 @@ impl StreamServer {
             loop {
                 if let Ok((socket, addr)) = listener.accept().await {
                     println!("Client connected: {}", addr);
-                    let socket_arc = Arc::new(Mutex::new(socket));
+                    // Frames go out on the write half, keyframe requests come in on the read half
+                    let (reader, writer) = socket.into_split();
+                    let writer = Arc::new(Mutex::new(writer));
 
                     // Add the new socket to the sockets map
-                    sockets_clone.lock().await.insert(addr, Arc::clone(&socket_arc));
+                    sockets_clone.lock().await.insert(addr, Arc::clone(&writer));
                     client_count_clone.fetch_add(1,Ordering::SeqCst);
 
                     let sender = sender.clone();
                     let sockets = Arc::clone(&sockets_clone);
                     let client_count = Arc::clone(&client_count_clone);
+                    let keyframe_requested = Arc::clone(&keyframe_requested);
 
                     // Spawn a task to handle the client
                     runtime_clone.spawn(async move {
-                        Self::handle_client(socket_arc, sender, sockets, client_count, addr).await;
+                        Self::handle_client(writer, reader, sender, sockets, client_count, keyframe_requested, addr).await;
                     });
                 }
             }
@@ -78,24 +120,18 @@ impl StreamServer {
 
     // Handle an individual client connection
     async fn handle_client(
-        socket: Arc<Mutex<TcpStream>>, // Wrapped in Arc<Mutex<>>
-        receiver: broadcast::Sender<Bytes>,
-        sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>,
+        writer: Arc<Mutex<OwnedWriteHalf>>, // Shared with `disconnect`
+        mut reader: OwnedReadHalf,
+        receiver: broadcast::Sender<Message>,
+        sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>>>,
         client_count: Arc<AtomicUsize>,
+        keyframe_requested: Arc<AtomicBool>,
         addr: SocketAddr,
     ) {
-        let mut receiver = receiver.clone().subscribe();
-
-        loop {
-            match receiver.recv().await {
-                Ok(frame) => {
-                    let mut socket = socket.lock().await;
-                    if socket.write_all(&frame).await.is_err() {
-                        break;
-                    }
-                }
-                Err(_) => break, // Channel closed
-            }
+        let receiver = receiver.clone().subscribe();
+        match Self::read_hello(&mut reader).await {
+            Ok(codecs) => Self::stream(&writer, &mut reader, receiver, &keyframe_requested, &codecs).await,
+            Err(e) => eprintln!("Refusing {}: {}", addr, e),
         }
 
         println!("Client disconnected: {}", addr);
@@ -103,48 +139,172 @@ impl StreamServer {
         let mut current_value = client_count.load(Ordering::SeqCst);
         while current_value > 0 {
             let new_value = current_value - 1;
-            if let Ok(_) = client_count.compare_exchange(current_value, new_value, Ordering::SeqCst, Ordering::SeqCst) {
+            if client_count.compare_exchange(current_value, new_value, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
                 break;  
             }
             current_value = client_count.load(Ordering::SeqCst);
         }
     }
 
-    // Broadcast a frame to all connected clients
-    pub fn broadcast_frame(&mut self, frame: Frame, is_streaming:bool) {
+    // Write frames to a receiver until it goes away, taking its keyframe requests
+    async fn stream(
+        writer: &Mutex<OwnedWriteHalf>,
+        reader: &mut OwnedReadHalf,
+        mut receiver: broadcast::Receiver<Message>,
+        keyframe_requested: &AtomicBool,
+        codecs: &[Codec],
+    ) {
+        keyframe_requested.store(true, Ordering::SeqCst);
+
+        // Stops when the channel is closed or the receiver goes away
+        let mut request = [0u8; 1];
+        loop {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Ok(message) => {
+                        let bytes = match &message.compressed {
+                            Some((codec, bytes)) if codecs.contains(codec) => bytes,
+                            _ => &message.plain,
+                        };
+                        let mut writer = writer.lock().await;
+                        if writer.write_all(bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Frames this receiver missed break its deltas
+                    Err(broadcast::error::RecvError::Lagged(_)) => keyframe_requested.store(true, Ordering::SeqCst),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                read = reader.read(&mut request) => match read {
+                    Ok(1) if request[0] == KEYFRAME_REQUEST => keyframe_requested.store(true, Ordering::SeqCst),
+                    Ok(1) => {}
+                    _ => break, // Closed by the receiver
+                },
+            }
+        }
+    }
+
+    // Codecs announced by a receiver when it connects
+    async fn read_hello(reader: &mut OwnedReadHalf) -> Result<Vec<Codec>, String> {
+        let mut hello = [0u8; HELLO_LEN];
+        match tokio::time::timeout(HELLO_TIMEOUT, reader.read_exact(&mut hello)).await {
+            Ok(Ok(_)) => accepted_codecs(&hello),
+            Ok(Err(e)) => Err(format!("Connection lost before the hello: {}", e)),
+            Err(_) => Err("No hello received".to_string()),
+        }
+    }
+
+    // Limit the broadcast rate to `fps` frames per second
+    pub fn set_fps(&mut self, fps: u32) {
+        self.interval = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+    }
+
+    // Broadcast a frame to all connected clients, returns whether the frame was sent.
+    // Frames without changes are skipped, apart from a periodic keepalive.
+    pub fn broadcast_frame(&mut self, frame: &Frame, is_streaming:bool) -> bool {
         if self.priority.load(Ordering::SeqCst) {
-            return;
+            return false;
         }
+        match (&mut self.unsent_damage, &frame.damage) {
+            (Some(unsent), Some(damage)) => unsent.extend_from_slice(damage),
+            (unsent, _) => *unsent = None,
+        }
+        let unsent_changes = self.unsent_damage.as_ref().is_none_or(|damage| !damage.is_empty());
+        if !unsent_changes
+            && !self.keyframe_requested.load(Ordering::SeqCst)
+            && self.last_broadcast.elapsed() < KEEPALIVE_INTERVAL
+        {
+            return false;
+        }
+        // Half an interval of slack absorbs wake-up jitter of the caller's own pacing
         let now = Instant::now();
-        if now.duration_since(self.time) >= Duration::from_millis(60){
-            if is_streaming{
-                let serialized_frame = match bincode::serialize(&frame) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        eprintln!("Failed to serialize frame: {}", e);
-                        return;
-                    }
-                };
-                let frame_size = (serialized_frame.len() as u32).to_be_bytes();
-        
-                // Prepare the buffer with size + serialized data
-                let mut buffer = Vec::with_capacity(4 + serialized_frame.len());
-                buffer.extend_from_slice(&frame_size);      // Frame size (4 bytes)
-                buffer.extend_from_slice(&serialized_frame);     // Add the frame data
-        
-                let _ = self.sender.send(Bytes::from(buffer));
+        if now + self.interval / 2 < self.next_send {
+            return false;
+        }
+        // Deadline-based throttle; after a stall restart from now instead of bursting
+        self.next_send += self.interval;
+        if self.next_send < now {
+            self.next_send = now + self.interval;
+        }
+
+        if is_streaming{
+            if self.keyframe_requested.swap(false, Ordering::SeqCst) {
+                self.encoder.request_keyframe();
             }
-            else {
-                // Send only the size prefix of 0 (4 bytes)
-                let frame_size = (0 as u32).to_be_bytes();
-                let mut buffer = Vec::with_capacity(4);
-                buffer.extend_from_slice(&frame_size); 
-    
-                let _ = self.sender.send(Bytes::from(buffer));
+            // The encoder only looks at the tiles that changed since the frame it last encoded
+            let mut frame = frame.clone();
+            frame.damage = self.unsent_damage.replace(Vec::new());
+            let update = self.encoder.encode(frame);
+
+            // Size (4 bytes) and codec tag, then the serialized update
+            let mut buffer = vec![0u8; 5];
+            if let Err(e) = bincode::serialize_into(&mut buffer, &update) {
+                eprintln!("Failed to serialize frame: {}", e);
+                return false;
             }
-            self.time = now;
+            let message_size = (buffer.len() as u32 - 4).to_be_bytes();
+            buffer[..4].copy_from_slice(&message_size);
+            buffer[4] = Codec::None.tag();
+            let compressed = self.compress(&buffer[5..]);
+
+            let _ = self.sender.send(Message { plain: Bytes::from(buffer), compressed });
+            self.last_broadcast = now;
+            true
+        }
+        else {
+            // Nothing is encoded meanwhile, the next update compares whole frames
+            self.unsent_damage = None;
+
+            // Send only the size prefix of 0 (4 bytes)
+            let frame_size = 0u32.to_be_bytes();
+            let mut buffer = Vec::with_capacity(4);
+            buffer.extend_from_slice(&frame_size); 
+
+            let _ = self.sender.send(Message { plain: Bytes::from(buffer), compressed: None });
+            false
+        }
+    }
+
+    // Compressed message for a serialized frame, None when compression is off or nobody listens
+    fn compress(&mut self, payload: &[u8]) -> Option<(Codec, Bytes)> {
+        let settings = self.compression;
+        if settings.codec == Codec::None || self.client_count.load(Ordering::SeqCst) == 0 {
+            return None;
         }
+        let started = Instant::now();
+        let compressed = match compress(payload, settings) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                eprintln!("{}", e);
+                return None;
+            }
+        };
+        self.totals.frames += 1;
+        self.totals.raw_bytes += payload.len() as u64;
+        self.totals.compressed_bytes += compressed.len() as u64;
+        self.totals.time += started.elapsed();
+
+        let mut buffer = Vec::with_capacity(5 + compressed.len());
+        buffer.extend_from_slice(&(compressed.len() as u32 + 1).to_be_bytes());
+        buffer.push(settings.codec.tag());
+        buffer.extend_from_slice(&compressed);
+        Some((settings.codec, Bytes::from(buffer)))
+    }
 
+    pub fn set_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+        self.totals = CompressionTotals::default();
+    }
+
+    // Compression ratio and milliseconds spent per frame since the last call, None if
+    // nothing was compressed
+    pub fn take_compression_stats(&mut self) -> Option<(f32, f32)> {
+        let totals = std::mem::take(&mut self.totals);
+        if totals.frames == 0 || totals.compressed_bytes == 0 {
+            return None;
+        }
+        let ratio = totals.raw_bytes as f32 / totals.compressed_bytes as f32;
+        Some((ratio, totals.time.as_secs_f32() * 1000.0 / totals.frames as f32))
     }
 
     // Disconnect all clients
@@ -171,7 +331,8 @@ impl StreamServer {
         println!("All clients disconnected and sockets closed");
     }
 
-    pub fn get_client_count(&self) -> usize {
-        self.client_count.load(Ordering::SeqCst)
+    // Shared counter so the client count can be read from outside the pipeline thread
+    pub fn client_counter(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.client_count)
     }
 }
\ No newline at end of file
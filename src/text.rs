@@ -0,0 +1,69 @@
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::screen::Frame;
+
+// Monospace font bundled with the binary, used to burn text into frames
+static FONT_DATA: &[u8] = include_bytes!("../assets/Hack-Regular.ttf");
+
+fn font() -> &'static FontRef<'static> {
+    static FONT: OnceLock<FontRef<'static>> = OnceLock::new();
+    FONT.get_or_init(|| FontRef::try_from_slice(FONT_DATA).expect("Bundled font is invalid"))
+}
+
+// Width and height in pixels of a single line of text at the given size
+pub fn measure_text(text: &str, size: f32) -> (u32, u32) {
+    let font = font().as_scaled(PxScale::from(size));
+    let width: f32 = text.chars().map(|c| font.h_advance(font.glyph_id(c))).sum();
+    (width.ceil() as u32, font.height().ceil() as u32)
+}
+
+// Blend a single line of text into an RGBA frame, (x, y) being its top-left corner
+pub fn draw_text(frame: &mut Frame, text: &str, x: i32, y: i32, size: f32, color: [u8; 4]) {
+    let font = font().as_scaled(PxScale::from(size));
+    let width = frame.width as i32;
+    let height = frame.height as i32;
+    let mut caret = x as f32;
+
+    for c in text.chars() {
+        let glyph_id = font.glyph_id(c);
+        let glyph = glyph_id.with_scale_and_position(PxScale::from(size), point(caret, y as f32 + font.ascent()));
+        caret += font.h_advance(glyph_id);
+
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue; // Whitespace has no outline
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let px = bounds.min.x as i32 + gx as i32;
+            let py = bounds.min.y as i32 + gy as i32;
+            if px < 0 || py < 0 || px >= width || py >= height {
+                return;
+            }
+            let alpha = coverage * color[3] as f32 / 255.0;
+            let index = ((py * width + px) * 4) as usize;
+            for (dst, src) in frame.data[index..index + 3].iter_mut().zip(color) {
+                *dst = (*dst as f32 + (src as f32 - *dst as f32) * alpha).round() as u8;
+            }
+        });
+    }
+}
+
+// Current UTC time as HH:MM:SS.mmm
+pub fn utc_clock() -> String {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        (seconds / 3600) % 24,
+        (seconds / 60) % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Elapsed time as H:MM:SS
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    format!("{}:{:02}:{:02}", seconds / 3600, (seconds / 60) % 60, seconds % 60)
+}
@@ -0,0 +1,373 @@
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use eframe::egui::{Key, Modifiers};
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{self, ConnectionExt as _, Keycode, Keysym, Window};
+use x11rb::protocol::xtest::ConnectionExt as _;
+use x11rb::rust_connection::RustConnection;
+
+// Display numbers tried for our own X server, away from the usual :0/:1
+const FIRST_DISPLAY: u32 = 90;
+const LAST_DISPLAY: u32 = 189;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Keysyms of the modifiers we press around forwarded keys
+const SHIFT_L: Keysym = 0xffe1;
+const CONTROL_L: Keysym = 0xffe3;
+const ALT_L: Keysym = 0xffe9;
+
+// An off-screen Xvfb server running one application; both are stopped when dropped
+pub struct VirtualDisplay {
+    name: String,
+    width: u32,
+    height: u32,
+    server: Child,
+    app: Child,
+    app_reaped: bool, // The shell's exit status was collected, its pid may belong to someone else now
+    input: InputInjector,
+}
+
+impl VirtualDisplay {
+    // Start Xvfb at the given size and run `command` (through the shell) inside it
+    pub fn launch(command: &str, width: u32, height: u32) -> Result<Self, String> {
+        if command.trim().is_empty() {
+            return Err("No command to launch".to_string());
+        }
+        if width == 0 || height == 0 {
+            return Err(format!("Invalid display size {}x{}", width, height));
+        }
+        let number = (FIRST_DISPLAY..=LAST_DISPLAY)
+            .find(|n| !Path::new(&format!("/tmp/.X{}-lock", n)).exists() && !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists())
+            .ok_or("No free X display number")?;
+        let name = format!(":{}", number);
+
+        let mut server = Command::new("Xvfb")
+            .args([&name, "-screen", "0", &format!("{}x{}x24", width, height), "-nolisten", "tcp"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Cannot start Xvfb (is it installed?): {}", e))?;
+
+        // The server takes a moment before it accepts connections
+        let started = Instant::now();
+        let conn = loop {
+            if let Ok((conn, _)) = x11rb::connect(Some(&name)) {
+                break conn;
+            }
+            if let Ok(Some(status)) = server.try_wait() {
+                return Err(format!("Xvfb exited during startup ({})", status));
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                let _ = server.kill();
+                let _ = server.wait();
+                return Err(format!("Xvfb did not start on {}", name));
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+        let input = match InputInjector::new(conn) {
+            Ok(input) => input,
+            Err(e) => {
+                let _ = server.kill();
+                let _ = server.wait();
+                return Err(e);
+            }
+        };
+
+        // In a process group of its own, so whatever the shell starts can be stopped with it
+        let app = match Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("DISPLAY", &name)
+            .stdin(Stdio::null())
+            .process_group(0)
+            .spawn()
+        {
+            Ok(app) => app,
+            Err(e) => {
+                let _ = server.kill();
+                let _ = server.wait();
+                return Err(format!("Cannot run {}: {}", command, e));
+            }
+        };
+        println!("Launched \"{}\" on virtual display {}", command, name);
+
+        Ok(Self { name, width, height, server, app, app_reaped: false, input })
+    }
+
+    // X display name, for capture
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Whether the application is still running
+    pub fn is_running(&mut self) -> bool {
+        match self.app.try_wait() {
+            Ok(None) => true,
+            Ok(Some(_)) => {
+                self.app_reaped = true;
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Move the pointer, `x` and `y` being fractions (0.0-1.0) of the display size
+    pub fn pointer_move(&mut self, x: f32, y: f32) -> Result<(), String> {
+        let x = (x.clamp(0.0, 1.0) * (self.width - 1) as f32).round() as i16;
+        let y = (y.clamp(0.0, 1.0) * (self.height - 1) as f32).round() as i16;
+        self.input.fake(xproto::MOTION_NOTIFY_EVENT, 0, x, y)
+    }
+
+    // X button numbers: 1 left, 2 middle, 3 right, 4/5 wheel up/down, 6/7 wheel left/right
+    pub fn pointer_button(&mut self, button: u8, pressed: bool) -> Result<(), String> {
+        let kind = if pressed { xproto::BUTTON_PRESS_EVENT } else { xproto::BUTTON_RELEASE_EVENT };
+        self.input.fake(kind, button, 0, 0)
+    }
+
+    // Press or release a non-text key, holding the modifiers that go with it
+    pub fn key(&mut self, key: Key, pressed: bool, modifiers: Modifiers) -> Result<(), String> {
+        let Some((keysym, is_text)) = key_to_keysym(key) else {
+            return Ok(());
+        };
+        if is_text && !(modifiers.ctrl || modifiers.alt) {
+            return Ok(()); // Arrives as text instead
+        }
+        let held: Vec<Keysym> = [(modifiers.shift, SHIFT_L), (modifiers.ctrl, CONTROL_L), (modifiers.alt, ALT_L)]
+            .into_iter()
+            .filter_map(|(down, keysym)| down.then_some(keysym))
+            .collect();
+        if pressed {
+            for &modifier in &held {
+                self.input.keysym(modifier, true)?;
+            }
+            self.input.keysym(keysym, true)
+        } else {
+            self.input.keysym(keysym, false)?;
+            for &modifier in held.iter().rev() {
+                self.input.keysym(modifier, false)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Type text as a sequence of key presses
+    pub fn text(&mut self, text: &str) -> Result<(), String> {
+        for c in text.chars() {
+            let keysym = char_to_keysym(c);
+            self.input.keysym(keysym, true)?;
+            self.input.keysym(keysym, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        // The group is numbered after the shell. Until the shell is reaped its pid stays taken,
+        // so the group id can't name anyone else; once reaped the id may be recycled, so leave it be.
+        if !self.app_reaped {
+            // SAFETY: kill only sends a signal
+            unsafe {
+                libc::kill(-(self.app.id() as libc::pid_t), libc::SIGKILL);
+            }
+            let _ = self.app.wait();
+        }
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+        println!("Virtual display {} stopped.", self.name);
+    }
+}
+
+// Synthesizes input on the virtual display with XTEST
+struct InputInjector {
+    conn: RustConnection,
+    root: Window,
+    min_keycode: Keycode,
+    keysyms_per_keycode: usize,
+    keysyms: Vec<Keysym>, // Keyboard mapping, keysyms_per_keycode entries per keycode from min_keycode
+}
+
+impl InputInjector {
+    fn new(conn: RustConnection) -> Result<Self, String> {
+        let root = conn.setup().roots[0].root;
+        let min_keycode = conn.setup().min_keycode;
+        let max_keycode = conn.setup().max_keycode;
+        conn.xtest_get_version(2, 2)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| format!("XTEST unavailable: {}", e))?;
+        let mapping = conn
+            .get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            conn,
+            root,
+            min_keycode,
+            keysyms_per_keycode: mapping.keysyms_per_keycode as usize,
+            keysyms: mapping.keysyms,
+        })
+    }
+
+    fn fake(&self, kind: u8, detail: u8, x: i16, y: i16) -> Result<(), String> {
+        self.conn
+            .xtest_fake_input(kind, detail, x11rb::CURRENT_TIME, self.root, x, y, 0)
+            .map_err(|e| e.to_string())?;
+        self.conn.flush().map_err(|e| e.to_string())
+    }
+
+    // Press or release the key producing `keysym`, with Shift when it is on the shifted level
+    fn keysym(&mut self, keysym: Keysym, pressed: bool) -> Result<(), String> {
+        let (keycode, shifted) = self.keycode(keysym)?;
+        let kind = if pressed { xproto::KEY_PRESS_EVENT } else { xproto::KEY_RELEASE_EVENT };
+        if shifted && pressed {
+            let (shift, _) = self.keycode(SHIFT_L)?;
+            self.fake(xproto::KEY_PRESS_EVENT, shift, 0, 0)?;
+        }
+        self.fake(kind, keycode, 0, 0)?;
+        if shifted && !pressed {
+            let (shift, _) = self.keycode(SHIFT_L)?;
+            self.fake(xproto::KEY_RELEASE_EVENT, shift, 0, 0)?;
+        }
+        Ok(())
+    }
+
+    fn keycode(&mut self, keysym: Keysym) -> Result<(Keycode, bool), String> {
+        let per_keycode = self.keysyms_per_keycode.max(1);
+        for (index, syms) in self.keysyms.chunks(per_keycode).enumerate() {
+            if let Some(level) = syms.iter().take(2).position(|&sym| sym == keysym) {
+                return Ok((self.min_keycode + index as u8, level == 1));
+            }
+        }
+
+        // Not on the keyboard: bind it to an unused keycode (needed for most non-ASCII text)
+        let spare = self.keysyms
+            .chunks(per_keycode)
+            .position(|syms| syms.iter().all(|&sym| sym == 0))
+            .ok_or_else(|| format!("No keycode available for keysym {:#x}", keysym))?;
+        let keycode = self.min_keycode + spare as u8;
+        let syms = vec![keysym; per_keycode];
+        self.conn
+            .change_keyboard_mapping(1, keycode, per_keycode as u8, &syms)
+            .map_err(|e| e.to_string())?
+            .check()
+            .map_err(|e| e.to_string())?;
+        self.keysyms[spare * per_keycode..(spare + 1) * per_keycode].copy_from_slice(&syms);
+        Ok((keycode, false))
+    }
+}
+
+fn char_to_keysym(c: char) -> Keysym {
+    match c as u32 {
+        0x0a | 0x0d => 0xff0d, // Return
+        0x09 => 0xff09,        // Tab
+        code @ (0x20..=0x7e | 0xa0..=0xff) => code,
+        code => 0x0100_0000 + code, // Unicode keysyms
+    }
+}
+
+// Keysym of a key, and whether the key normally produces text (letters and digits)
+fn key_to_keysym(key: Key) -> Option<(Keysym, bool)> {
+    let keysym = match key {
+        Key::ArrowDown => 0xff54,
+        Key::ArrowLeft => 0xff51,
+        Key::ArrowRight => 0xff53,
+        Key::ArrowUp => 0xff52,
+        Key::Escape => 0xff1b,
+        Key::Tab => 0xff09,
+        Key::Backspace => 0xff08,
+        Key::Enter => 0xff0d,
+        Key::Insert => 0xff63,
+        Key::Delete => 0xffff,
+        Key::Home => 0xff50,
+        Key::End => 0xff57,
+        Key::PageUp => 0xff55,
+        Key::PageDown => 0xff56,
+        Key::F1 => 0xffbe,
+        Key::F2 => 0xffbf,
+        Key::F3 => 0xffc0,
+        Key::F4 => 0xffc1,
+        Key::F5 => 0xffc2,
+        Key::F6 => 0xffc3,
+        Key::F7 => 0xffc4,
+        Key::F8 => 0xffc5,
+        Key::F9 => 0xffc6,
+        Key::F10 => 0xffc7,
+        Key::F11 => 0xffc8,
+        Key::F12 => 0xffc9,
+        other => {
+            // Single letters and digits, named "A" or "0" by egui
+            let name = other.name();
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_alphanumeric() => return Some((c.to_ascii_lowercase() as Keysym, true)),
+                _ => return None,
+            }
+        }
+    };
+    Some((keysym, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_characters_to_keysyms() {
+        assert_eq!(char_to_keysym('a'), 0x61);
+        assert_eq!(char_to_keysym('~'), 0x7e);
+        assert_eq!(char_to_keysym('é'), 0xe9);
+        assert_eq!(char_to_keysym('\n'), 0xff0d);
+        assert_eq!(char_to_keysym('\r'), 0xff0d);
+        assert_eq!(char_to_keysym('\t'), 0xff09);
+        assert_eq!(char_to_keysym('€'), 0x0100_20ac);
+    }
+
+    #[test]
+    fn maps_keys_to_keysyms() {
+        assert_eq!(key_to_keysym(Key::Enter), Some((0xff0d, false)));
+        assert_eq!(key_to_keysym(Key::ArrowLeft), Some((0xff51, false)));
+        assert_eq!(key_to_keysym(Key::F12), Some((0xffc9, false)));
+        assert_eq!(key_to_keysym(Key::A), Some((0x61, true)));
+        assert_eq!(key_to_keysym(Key::Num7), Some((0x37, true)));
+        assert_eq!(key_to_keysym(Key::Minus), None);
+    }
+
+    #[test]
+    fn rejects_bad_launches() {
+        assert!(VirtualDisplay::launch("  ", 640, 480).is_err());
+        assert!(VirtualDisplay::launch("xterm", 0, 480).is_err());
+        assert!(VirtualDisplay::launch("xterm", 640, 0).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn runs_the_command_and_moves_the_pointer() {
+        let mut display = VirtualDisplay::launch("sleep 1", 200, 100).unwrap();
+        assert!(display.is_running());
+
+        // The fake motion goes through another connection, give the server a moment
+        display.pointer_move(0.5, 1.0).unwrap();
+        let (conn, screen) = x11rb::connect(Some(display.name())).unwrap();
+        let root = conn.setup().roots[screen].root;
+        let started = Instant::now();
+        loop {
+            let pointer = conn.query_pointer(root).unwrap().reply().unwrap();
+            if (pointer.root_x, pointer.root_y) == (100, 99) {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(2), "pointer is at {},{}", pointer.root_x, pointer.root_y);
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let started = Instant::now();
+        while display.is_running() {
+            assert!(started.elapsed() < Duration::from_secs(5), "command did not exit");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use std::time::{Duration, Instant};
+use image::RgbaImage;
+use image::imageops::{self, FilterType};
+use x11rb::connection::Connection;
+use x11rb::protocol::randr::ConnectionExt as _;
+use x11rb::protocol::xfixes::ConnectionExt as _;
+use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt as _, KeyButMask, Window};
+use x11rb::rust_connection::RustConnection;
+use crate::screen::{Frame, CursorOptions, blend_image, draw_polyline};
+
+// Position and size of a monitor on the X screen
+#[derive(Clone, Copy, PartialEq)]
+pub struct MonitorRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Monitors in the same order as scrap's Display::all (RandR monitors of each screen)
+pub fn monitor_rects() -> Result<Vec<MonitorRect>, String> {
+    let (conn, _) = x11rb::connect(None).map_err(|e| format!("Cannot connect to X server: {}", e))?;
+    monitor_rects_on(&conn)
+}
+
+// Same as monitor_rects, reusing an open connection
+pub fn monitor_rects_on(conn: &RustConnection) -> Result<Vec<MonitorRect>, String> {
+    let mut rects = Vec::new();
+    for screen in &conn.setup().roots {
+        let monitors = conn
+            .randr_get_monitors(screen.root, true)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| format!("RandR monitors unavailable: {}", e))?
+            .monitors;
+        rects.extend(monitors.iter().map(|monitor| MonitorRect {
+            x: monitor.x as i32,
+            y: monitor.y as i32,
+            width: monitor.width as u32,
+            height: monitor.height as u32,
+        }));
+    }
+    Ok(rects)
+}
+
+// Top-level application window as listed by the window manager
+#[derive(Clone, PartialEq)]
+pub struct WindowInfo {
+    pub id: Window,
+    pub title: String,
+    pub class: String,
+}
+
+// Application windows in the window manager's stacking order (EWMH _NET_CLIENT_LIST)
+pub fn list_windows() -> Result<Vec<WindowInfo>, String> {
+    let (conn, screen) = x11rb::connect(None).map_err(|e| format!("Cannot connect to X server: {}", e))?;
+    let root = conn.setup().roots[screen].root;
+    let client_list = atom(&conn, "_NET_CLIENT_LIST")?;
+    let ids: Vec<Window> = conn
+        .get_property(false, root, client_list, AtomEnum::WINDOW, 0, u32::MAX)
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?
+        .value32()
+        .ok_or("The window manager does not publish a window list")?
+        .collect();
+
+    let net_wm_name = atom(&conn, "_NET_WM_NAME")?;
+    let mut windows = Vec::new();
+    for id in ids {
+        // Windows can disappear while we walk the list
+        let Ok(title) = window_title(&conn, id, net_wm_name) else {
+            continue;
+        };
+        let class = text_property(&conn, id, AtomEnum::WM_CLASS.into())
+            .map(|class| class.split('\0').nth(1).unwrap_or_default().to_string())
+            .unwrap_or_default();
+        windows.push(WindowInfo { id, title, class });
+    }
+    Ok(windows)
+}
+
+// Window that currently has the focus, preferring the window manager's view of it
+pub fn active_window(conn: &RustConnection, root: Window) -> Result<Window, String> {
+    let active = atom(conn, "_NET_ACTIVE_WINDOW")?;
+    let reply = conn
+        .get_property(false, root, active, AtomEnum::WINDOW, 0, 1)
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?;
+    if let Some(window) = reply.value32().and_then(|mut values| values.next()).filter(|&window| window != x11rb::NONE) {
+        return Ok(window);
+    }
+    let focus = conn
+        .get_input_focus()
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?
+        .focus;
+    if focus == x11rb::NONE || focus == root || focus == 1 {
+        return Err("No window has the focus".to_string()); // 1 is PointerRoot
+    }
+    Ok(focus)
+}
+
+// Application windows from bottom to top (EWMH _NET_CLIENT_LIST_STACKING)
+pub fn stacked_windows(conn: &RustConnection, root: Window) -> Result<Vec<Window>, String> {
+    let stacking = atom(conn, "_NET_CLIENT_LIST_STACKING")?;
+    Ok(conn
+        .get_property(false, root, stacking, AtomEnum::WINDOW, 0, u32::MAX)
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?
+        .value32()
+        .ok_or("The window manager does not publish a window list")?
+        .collect())
+}
+
+// Whether `window` belongs to this process, according to its _NET_WM_PID
+pub fn is_own_window(conn: &RustConnection, window: Window) -> bool {
+    let Ok(net_wm_pid) = atom(conn, "_NET_WM_PID") else {
+        return false;
+    };
+    conn.get_property(false, window, net_wm_pid, AtomEnum::CARDINAL, 0, 1)
+        .ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .and_then(|reply| reply.value32().and_then(|mut values| values.next()))
+        == Some(std::process::id())
+}
+
+fn window_title(conn: &RustConnection, window: Window, net_wm_name: Atom) -> Result<String, String> {
+    let title = text_property(conn, window, net_wm_name)?;
+    if !title.is_empty() {
+        return Ok(title);
+    }
+    text_property(conn, window, AtomEnum::WM_NAME.into())
+}
+
+fn text_property(conn: &RustConnection, window: Window, property: Atom) -> Result<String, String> {
+    let reply = conn
+        .get_property(false, window, property, AtomEnum::ANY, 0, 1024)
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&reply.value).into_owned())
+}
+
+pub fn atom(conn: &RustConnection, name: &str) -> Result<Atom, String> {
+    Ok(conn
+        .intern_atom(false, name.as_bytes())
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?
+        .atom)
+}
+
+const RIPPLE_DURATION: Duration = Duration::from_millis(500);
+
+// Reads the pointer image and button state with XFixes and paints it into frames
+pub struct CursorTracker {
+    conn: RustConnection,
+    root: Window,
+    serial: u32,              // Serial of the cached cursor image
+    pointer: (i32, i32, u16), // Last queried position and button mask
+    sprite: CursorSprite,
+}
+
+impl CursorTracker {
+    // `display` is an X display name such as ":1", None for $DISPLAY
+    pub fn new(display: Option<&str>) -> Result<Self, String> {
+        let (conn, screen) = x11rb::connect(display).map_err(|e| format!("Cannot connect to X server: {}", e))?;
+        let root = conn.setup().roots[screen].root;
+        conn.xfixes_query_version(4, 0)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| format!("XFixes unavailable: {}", e))?;
+        Ok(Self { conn, root, serial: 0, pointer: (0, 0, 0), sprite: CursorSprite::new() })
+    }
+
+    // Whether the pointer moved, was clicked or is still animating since the last call
+    pub fn changed(&mut self, options: &CursorOptions) -> Result<bool, String> {
+        if !options.show {
+            return Ok(self.sprite.drawn.take().is_some());
+        }
+        let pointer = self.conn
+            .query_pointer(self.root)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?;
+        let state = (pointer.root_x as i32, pointer.root_y as i32, u16::from(pointer.mask));
+        let changed = state != self.pointer || !self.sprite.ripples.is_empty();
+        self.pointer = state;
+        Ok(changed)
+    }
+
+    // Frame area covered by the last composite as (x0, y0, x1, y1)
+    pub fn drawn_area(&self) -> Option<(i32, i32, i32, i32)> {
+        self.sprite.drawn
+    }
+
+    // Paint the pointer into a frame showing the area of the screen starting at `origin`
+    pub fn composite(&mut self, frame: &mut Frame, origin: (i32, i32), options: &CursorOptions) -> Result<(), String> {
+        self.sprite.drawn = None;
+        if !options.show {
+            return Ok(());
+        }
+        let cursor = self.conn
+            .xfixes_get_cursor_image()
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?;
+        let pointer = self.conn
+            .query_pointer(self.root)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?;
+
+        // Only convert the image when the cursor shape changes
+        if cursor.cursor_serial != self.serial || self.sprite.image.is_none() {
+            let hotspot = (cursor.xhot as i32, cursor.yhot as i32);
+            self.sprite.set_image(cursor.width as u32, cursor.height as u32, &cursor.cursor_image, hotspot);
+            self.serial = cursor.cursor_serial;
+        }
+
+        let buttons = u16::from(KeyButMask::BUTTON1 | KeyButMask::BUTTON2 | KeyButMask::BUTTON3);
+        let pressed = u16::from(pointer.mask) & buttons != 0;
+        self.sprite.paint(frame, (cursor.x as i32, cursor.y as i32), pressed, origin, options);
+        Ok(())
+    }
+}
+
+// Pointer image with its halo and click ripples, drawn without talking to the X server
+struct CursorSprite {
+    image: Option<RgbaImage>, // Cursor image with straight alpha
+    hotspot: (i32, i32),
+    scaled: Option<(f32, RgbaImage)>,
+    was_pressed: bool,
+    ripples: Vec<(i32, i32, Instant)>, // Root coordinates and start of each click ripple
+    drawn: Option<(i32, i32, i32, i32)>, // Frame area covered by the last paint (x0, y0, x1, y1)
+}
+
+impl CursorSprite {
+    fn new() -> Self {
+        Self { image: None, hotspot: (0, 0), scaled: None, was_pressed: false, ripples: Vec::new(), drawn: None }
+    }
+
+    // Take a cursor image as XFixes gives it, premultiplied ARGB
+    fn set_image(&mut self, width: u32, height: u32, argb: &[u32], hotspot: (i32, i32)) {
+        let mut data = Vec::with_capacity(argb.len() * 4);
+        for pixel in argb {
+            let [b, g, r, a] = pixel.to_le_bytes();
+            let unpremultiply = |c: u8| if a == 0 { 0 } else { (c as u32 * 255 / a as u32).min(255) as u8 };
+            data.extend_from_slice(&[unpremultiply(r), unpremultiply(g), unpremultiply(b), a]);
+        }
+        self.image = RgbaImage::from_raw(width, height, data);
+        self.hotspot = hotspot;
+        self.scaled = None;
+    }
+
+    // Draw the pointer at `position` (root coordinates) into a frame starting at `origin`
+    fn paint(&mut self, frame: &mut Frame, position: (i32, i32), pressed: bool, origin: (i32, i32), options: &CursorOptions) {
+        self.drawn = None;
+        let x = position.0 - origin.0;
+        let y = position.1 - origin.1;
+        let scale = options.scale.max(0.5);
+
+        if options.halo {
+            let diameter = 48.0 * scale;
+            draw_polyline(frame, &[(x as f32, y as f32)], diameter, [255, 230, 0, 90]);
+            let radius = diameter as i32 / 2 + 1;
+            extend_area(&mut self.drawn, (x - radius, y - radius, x + radius, y + radius));
+        }
+
+        // Start a ripple on every new button press
+        if pressed && !self.was_pressed && options.ripples {
+            self.ripples.push((position.0, position.1, Instant::now()));
+        }
+        self.was_pressed = pressed;
+        self.ripples.retain(|(_, _, start)| start.elapsed() < RIPPLE_DURATION);
+        for (rx, ry, start) in &self.ripples {
+            let progress = start.elapsed().as_secs_f32() / RIPPLE_DURATION.as_secs_f32();
+            let radius = (8.0 + 32.0 * progress) * scale;
+            let center = ((rx - origin.0) as f32, (ry - origin.1) as f32);
+            let circle: Vec<(f32, f32)> = (0..=32)
+                .map(|i| {
+                    let angle = i as f32 / 32.0 * std::f32::consts::TAU;
+                    (center.0 + radius * angle.cos(), center.1 + radius * angle.sin())
+                })
+                .collect();
+            draw_polyline(frame, &circle, 3.0 * scale, [255, 60, 0, ((1.0 - progress) * 255.0) as u8]);
+            let (cx, cy, reach) = (center.0 as i32, center.1 as i32, (radius + 3.0 * scale) as i32 + 1);
+            extend_area(&mut self.drawn, (cx - reach, cy - reach, cx + reach, cy + reach));
+        }
+
+        let Some(image) = &self.image else {
+            return;
+        };
+        let left = x - (self.hotspot.0 as f32 * scale) as i32;
+        let top = y - (self.hotspot.1 as f32 * scale) as i32;
+        let right = left + (image.width() as f32 * scale).ceil() as i32;
+        let bottom = top + (image.height() as f32 * scale).ceil() as i32;
+        extend_area(&mut self.drawn, (left, top, right, bottom));
+        if (scale - 1.0).abs() < f32::EPSILON {
+            blend_image(frame, image, x - self.hotspot.0, y - self.hotspot.1, 1.0);
+        } else {
+            if self.scaled.as_ref().map(|(cached, _)| *cached) != Some(scale) {
+                let width = ((image.width() as f32 * scale) as u32).max(1);
+                let height = ((image.height() as f32 * scale) as u32).max(1);
+                self.scaled = Some((scale, imageops::resize(image, width, height, FilterType::Triangle)));
+            }
+            if let Some((_, scaled)) = &self.scaled {
+                let hotspot_x = (self.hotspot.0 as f32 * scale) as i32;
+                let hotspot_y = (self.hotspot.1 as f32 * scale) as i32;
+                blend_image(frame, scaled, x - hotspot_x, y - hotspot_y, 1.0);
+            }
+        }
+    }
+}
+
+// Grow an (x0, y0, x1, y1) area to include another one
+fn extend_area(area: &mut Option<(i32, i32, i32, i32)>, other: (i32, i32, i32, i32)) {
+    *area = Some(match *area {
+        Some((x0, y0, x1, y1)) => (x0.min(other.0), y0.min(other.1), x1.max(other.2), y1.max(other.3)),
+        None => other,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::PixelFormat;
+
+    // Opaque red 4x4 pointer with its hotspot one pixel in
+    fn sprite() -> CursorSprite {
+        let mut sprite = CursorSprite::new();
+        sprite.set_image(4, 4, &[0xffff_0000; 16], (1, 1));
+        sprite
+    }
+
+    fn frame(size: u32) -> Frame {
+        Frame::new(vec![0; (size * size * 4) as usize], size, size, PixelFormat::Bgra)
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> [u8; 4] {
+        let index = y * frame.stride + x * 4;
+        frame.data[index..index + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn unpremultiplies_the_cursor_image() {
+        let mut sprite = CursorSprite::new();
+        sprite.set_image(2, 1, &[0x8080_0000, 0x0000_0000], (0, 0));
+        let image = sprite.image.unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 128]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn draws_at_the_hotspot() {
+        let mut sprite = sprite();
+        let mut frame = frame(32);
+        sprite.paint(&mut frame, (15, 15), false, (5, 5), &CursorOptions::new());
+        assert_eq!(sprite.drawn, Some((9, 9, 13, 13)));
+        assert_eq!(pixel(&frame, 9, 9), [0, 0, 255, 0]);
+        assert_eq!(pixel(&frame, 12, 12), [0, 0, 255, 0]);
+        assert_eq!(pixel(&frame, 13, 13), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn scales_around_the_hotspot() {
+        let mut sprite = sprite();
+        let mut frame = frame(32);
+        let options = CursorOptions { scale: 2.0, ..CursorOptions::new() };
+        sprite.paint(&mut frame, (10, 10), false, (0, 0), &options);
+        assert_eq!(sprite.drawn, Some((8, 8, 16, 16)));
+        assert_eq!(pixel(&frame, 8, 8), [0, 0, 255, 0]);
+        assert_eq!(pixel(&frame, 15, 15), [0, 0, 255, 0]);
+        assert_eq!(pixel(&frame, 16, 16), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn halo_surrounds_the_pointer() {
+        let mut sprite = sprite();
+        let mut frame = frame(64);
+        let options = CursorOptions { halo: true, ..CursorOptions::new() };
+        sprite.paint(&mut frame, (32, 32), false, (0, 0), &options);
+        assert_eq!(sprite.drawn, Some((7, 7, 57, 57)));
+        let tinted = pixel(&frame, 52, 32);
+        assert!(tinted[2] > 0 && tinted[1] > 0 && tinted[0] == 0, "{:?}", tinted);
+        assert_eq!(pixel(&frame, 60, 32), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ripples_start_on_new_presses_only() {
+        let mut sprite = sprite();
+        let mut frame = frame(64);
+        sprite.paint(&mut frame, (32, 32), true, (0, 0), &CursorOptions::new());
+        assert!(sprite.ripples.is_empty(), "ripples are off");
+
+        let options = CursorOptions { ripples: true, ..CursorOptions::new() };
+        sprite.paint(&mut frame, (32, 32), false, (0, 0), &options);
+        sprite.paint(&mut frame, (32, 32), true, (0, 0), &options);
+        sprite.paint(&mut frame, (32, 32), true, (0, 0), &options);
+        assert_eq!(sprite.ripples.len(), 1);
+
+        // A fresh ring is 8 pixels out and the drawn area reaches past it
+        let (x0, y0, x1, y1) = sprite.drawn.unwrap();
+        assert!(x0 <= 32 - 11 && y0 <= 32 - 11 && x1 >= 32 + 11 && y1 >= 32 + 11);
+        assert!(pixel(&frame, 40, 32)[2] > 0);
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn composites_the_server_cursor() {
+        let display = crate::virtual_display::VirtualDisplay::launch("sleep 30", 64, 48).unwrap();
+        let mut tracker = CursorTracker::new(Some(display.name())).unwrap();
+        let mut frame = frame(64);
+
+        // Xvfb starts with the pointer in the middle of the screen
+        tracker.composite(&mut frame, (0, 0), &CursorOptions::new()).unwrap();
+        let (x0, y0, x1, y1) = tracker.drawn_area().unwrap();
+        assert!(x0 <= 32 && x1 > 32 && y0 <= 24 && y1 > 24);
+        assert!(frame.data.chunks(4).any(|pixel| pixel[..3] != [0, 0, 0]));
+
+        let hidden = CursorOptions { show: false, ..CursorOptions::new() };
+        assert!(tracker.changed(&hidden).unwrap(), "hiding has to repaint where it was drawn");
+        assert_eq!(tracker.drawn_area(), None);
+    }
+}
@@ -0,0 +1,796 @@
+use std::fs::{self, OpenOptions};
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread::{self, JoinHandle};
+use memmap2::MmapMut;
+use tokio::sync::watch;
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::composite::{self, ConnectionExt as _};
+use x11rb::protocol::damage::{self, ConnectionExt as _};
+use x11rb::protocol::randr::{self, ConnectionExt as _};
+use x11rb::protocol::shm::{self, ConnectionExt as _};
+use x11rb::protocol::xfixes::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{Atom, ChangeWindowAttributesAux, ConnectionExt as _, EventMask, ImageFormat, Pixmap, Window};
+use x11rb::rust_connection::RustConnection;
+use crate::screen::{
+    CaptureState, CursorOptions, DamageRect, Frame, FrameRecycler, FrameSource, Pacer,
+    MAX_CAPTURE_RETRIES, clip_area, message_slate, wait_before_retry,
+};
+use crate::convert::{PixelFormat, copy_rows};
+use crate::x11::{CursorTracker, active_window, atom, is_own_window, monitor_rects_on, stacked_windows};
+
+// Above this many damaged rectangles a single grab of their bounding box is cheaper
+const MAX_GRABS_PER_FRAME: usize = 16;
+
+// What an X11 capture shows
+#[derive(Clone, Copy, PartialEq)]
+pub enum CaptureTarget {
+    Monitor(usize),
+    Window(Window),
+    FocusedWindow, // Switches to whichever window gets the focus
+    Screen,        // The whole X screen
+}
+
+// Latest frame and the areas that changed since the pipeline last took it
+struct Shared {
+    frame: Frame,
+    damage: Vec<DamageRect>,
+    generation: u64, // Bumped for every published frame
+}
+
+// Monitor or window capture talking to the X server directly: XDamage says what changed and
+// only those areas are read back, through shared memory when the server allows it
+pub struct X11Capture {
+    shared: Arc<Mutex<Shared>>,
+    damage: Vec<DamageRect>, // Damage of the frame last returned by receive_frame
+    generation: u64,         // Generation of that frame
+    fps: Arc<AtomicU32>,
+    cursor: watch::Sender<CursorOptions>,
+    state: watch::Receiver<CaptureState>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl X11Capture {
+    pub fn new(target: CaptureTarget, fps: u32) -> Result<Self, String> {
+        Self::on_display(None, target, fps)
+    }
+
+    // Capture from another X server, `display` being a name such as ":1" (None for $DISPLAY)
+    pub fn on_display(display: Option<String>, target: CaptureTarget, fps: u32) -> Result<Self, String> {
+        let shared = Arc::new(Mutex::new(Shared {
+            frame: Frame::empty(),
+            damage: Vec::new(),
+            generation: 0,
+        }));
+        let output = Arc::clone(&shared);
+        let fps = Arc::new(AtomicU32::new(fps));
+        let target_fps = Arc::clone(&fps);
+        let (cursor, cursor_options) = watch::channel(CursorOptions::new());
+        let (state_tx, state) = watch::channel(CaptureState::Starting);
+        let running = Arc::new(AtomicBool::new(true));
+        let keep_running = Arc::clone(&running);
+
+        let thread = thread::spawn(move || {
+            let mut cursor_tracker = match CursorTracker::new(display.as_deref()) {
+                Ok(tracker) => Some(tracker),
+                Err(e) => {
+                    eprintln!("Pointer will not be captured: {}", e);
+                    None
+                }
+            };
+
+            // Hands back the frame it replaced, receivers only ever clone it
+            let publish = |frame: Frame, damage: &[DamageRect]| {
+                let mut shared = output.lock().unwrap_or_else(|e| e.into_inner());
+                shared.damage.extend_from_slice(damage);
+                shared.generation += 1;
+                std::mem::replace(&mut shared.frame, frame)
+            };
+
+            let mut attempt = 0;
+            let mut slate_size = (1280, 720);
+            let mut recycler = FrameRecycler::default();
+            while keep_running.load(Ordering::Relaxed) {
+                let reason = match Grabber::open(display.as_deref(), target) {
+                    Ok(mut grabber) => {
+                        let _ = state_tx.send(CaptureState::Running);
+                        let mut pacer = Pacer::new(target_fps.load(Ordering::Relaxed));
+                        let mut hidden_shown = false;
+                        let reason = loop {
+                            if !keep_running.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            pacer.set_fps(target_fps.load(Ordering::Relaxed));
+
+                            // Moves, resizes, focus and RandR changes don't show up as damage
+                            let moved = match grabber.follow() {
+                                Ok(moved) => moved,
+                                Err(reason) => break reason,
+                            };
+                            slate_size = (grabber.area.width, grabber.area.height);
+
+                            let mut damage = match grabber.grab() {
+                                Ok(damage) => damage,
+                                Err(reason) => break reason,
+                            };
+                            let options = *cursor_options.borrow();
+                            let pointer_changed = match &mut cursor_tracker {
+                                Some(tracker) => tracker.changed(&options).unwrap_or(false),
+                                None => false,
+                            };
+
+                            if grabber.hidden {
+                                // Receivers keep the last picture, unless the window was hidden
+                                // before anything of its current size was read
+                                if grabber.first_grab && !hidden_shown {
+                                    let full = [DamageRect { x: 0, y: 0, width: slate_size.0, height: slate_size.1 }];
+                                    publish(message_slate(slate_size, "Window hidden"), &full);
+                                    recycler = FrameRecycler::default();
+                                    hidden_shown = true;
+                                }
+                            } else if !damage.is_empty() || pointer_changed || moved {
+                                attempt = 0;
+                                hidden_shown = false;
+                                let (width, height) = (grabber.area.width, grabber.area.height);
+                                let before = cursor_tracker
+                                    .as_ref()
+                                    .and_then(|tracker| tracker.drawn_area())
+                                    .and_then(|area| clip_area(area, width, height));
+                                // The recycled frame lacks the new damage and still shows the old pointer;
+                                // after a move the whole picture may have shifted
+                                let mut changed = damage.clone();
+                                changed.extend(before);
+                                let mut frame = grabber.frame(&mut recycler, (!moved).then_some(changed.as_slice()));
+                                if let Some(tracker) = &mut cursor_tracker {
+                                    // Both where the pointer was and where it is now need repainting
+                                    if let Err(e) = tracker.composite(&mut frame, grabber.area.origin, &options) {
+                                        eprintln!("Error drawing pointer: {}", e);
+                                    }
+                                    damage.extend(before);
+                                    damage.extend(tracker.drawn_area().and_then(|area| clip_area(area, width, height)));
+                                }
+                                recycler.recycle(publish(frame, &damage));
+                            }
+
+                            // Wait for the next capture deadline (to control FPS)
+                            pacer.wait();
+                        };
+                        reason
+                    }
+                    Err(reason) => reason,
+                };
+
+                // Keep receivers fed with a slate while the capture is down
+                attempt += 1;
+                recycler = FrameRecycler::default();
+                let full = [DamageRect { x: 0, y: 0, width: slate_size.0, height: slate_size.1 }];
+                if attempt > MAX_CAPTURE_RETRIES {
+                    eprintln!("Giving up on X11 capture: {}", reason);
+                    publish(message_slate(slate_size, "Screen capture stopped"), &full);
+                    let _ = state_tx.send(CaptureState::Failed(reason));
+                    return;
+                }
+                eprintln!("X11 capture interrupted ({}), retry {} of {}", reason, attempt, MAX_CAPTURE_RETRIES);
+                publish(message_slate(slate_size, "Reconnecting..."), &full);
+                let _ = state_tx.send(CaptureState::Restarting { attempt, reason });
+                wait_before_retry(attempt, &keep_running);
+            }
+        });
+
+        Ok(X11Capture {
+            shared,
+            damage: Vec::new(),
+            generation: 0,
+            fps,
+            cursor,
+            state,
+            running,
+            thread: Some(thread),
+        })
+    }
+}
+
+// Stop the capture thread and wait for it, so the X resources are released before the next source starts
+impl Drop for X11Capture {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("X11 capture thread panicked.");
+            }
+        }
+    }
+}
+
+impl FrameSource for X11Capture {
+    fn receive_frame(&mut self) -> Option<Frame> {
+        // Frame and damage are taken together so they always match
+        let mut shared = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        if shared.generation == self.generation || shared.frame.data.is_empty() {
+            return None;
+        }
+        self.generation = shared.generation;
+        self.damage = std::mem::take(&mut shared.damage);
+        Some(shared.frame.clone())
+    }
+
+    fn set_fps(&mut self, fps: u32) {
+        self.fps.store(fps, Ordering::Relaxed);
+    }
+
+    fn set_cursor(&mut self, options: CursorOptions) {
+        let _ = self.cursor.send(options);
+    }
+
+    fn take_damage(&mut self) -> Option<Vec<DamageRect>> {
+        Some(std::mem::take(&mut self.damage))
+    }
+
+    fn state(&self) -> Option<watch::Receiver<CaptureState>> {
+        Some(self.state.clone())
+    }
+}
+
+// Shared memory segment the server writes images into
+struct ShmSegment {
+    seg: shm::Seg,
+    map: MmapMut,
+}
+
+// Where the captured pixels are read from and where they sit on the screen
+#[derive(Clone, Copy, PartialEq)]
+struct Area {
+    drawable: Window,
+    offset: (i32, i32), // Top-left corner of the capture inside the drawable
+    origin: (i32, i32), // Same corner in root coordinates
+    border: i32,        // Border width of a window, which its pixmap includes
+    width: u32,
+    height: u32,
+}
+
+// X resources for one capture target; the server frees them when the connection closes
+struct Grabber {
+    conn: RustConnection,
+    root: Window,
+    root_size: (u32, u32), // Follows RandR screen changes
+    target: CaptureTarget,
+    area: Area,
+    damage: Option<damage::Damage>, // Watches area.drawable
+    region: xfixes::Region,
+    shm: Option<ShmSegment>,
+    composite: bool,        // The server can name window pixmaps
+    pixmap: Option<Pixmap>, // Contents of the captured window, overlapping windows excluded
+    hidden: bool,           // The captured window is unmapped, nothing new can be read
+    active_window_atom: Atom, // Watched on the root when following the focus
+    damaged: bool,          // XDamage reported changes since the last grab
+    stale: bool,            // An event may have moved the target, resolve it again
+    bgra: Vec<u8>,    // Current content of the area, as the server sends it
+    first_grab: bool, // Nothing has been read yet, grab everything
+}
+
+impl Grabber {
+    fn open(display: Option<&str>, target: CaptureTarget) -> Result<Self, String> {
+        let (conn, screen) = x11rb::connect(display).map_err(|e| format!("Cannot connect to X server: {}", e))?;
+        let screen = &conn.setup().roots[screen];
+        let root = screen.root;
+        let root_size = (screen.width_in_pixels as u32, screen.height_in_pixels as u32);
+
+        conn.xfixes_query_version(4, 0)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| format!("XFixes unavailable: {}", e))?;
+        conn.damage_query_version(1, 1)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| format!("XDamage unavailable: {}", e))?;
+        let region = conn.generate_id().map_err(|e| e.to_string())?;
+        conn.xfixes_create_region(region, &[])
+            .map_err(|e| e.to_string())?
+            .check()
+            .map_err(|e| e.to_string())?;
+
+        // Reading a window from the screen would pick up whatever overlaps it, so windows are
+        // read from the off-screen copy Composite keeps (NameWindowPixmap needs 0.2)
+        let composite = conn
+            .composite_query_version(0, 4)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .is_ok_and(|version| (version.major_version, version.minor_version) >= (0, 2));
+        if matches!(target, CaptureTarget::Window(_) | CaptureTarget::FocusedWindow) && !composite {
+            return Err("Window capture needs the Composite extension".to_string());
+        }
+
+        // The target only moves on events: monitors added, removed or resized, the screen size
+        // changing with them, or another window getting the focus
+        let changes = randr::NotifyMask::SCREEN_CHANGE | randr::NotifyMask::CRTC_CHANGE | randr::NotifyMask::OUTPUT_CHANGE;
+        conn.randr_select_input(root, changes).map_err(|e| e.to_string())?;
+        let active_window_atom = if target == CaptureTarget::FocusedWindow {
+            conn.change_window_attributes(root, &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE))
+                .map_err(|e| e.to_string())?;
+            atom(&conn, "_NET_ACTIVE_WINDOW")?
+        } else {
+            x11rb::NONE
+        };
+
+        // Sized for the screen; parts of larger windows go through GetImage
+        let shm = match attach_shm(&conn, root_size.0 as usize * root_size.1 as usize * 4) {
+            Ok(segment) => Some(segment),
+            Err(e) => {
+                eprintln!("XShm unavailable, falling back to GetImage: {}", e);
+                None
+            }
+        };
+
+        let mut grabber = Self {
+            conn,
+            root,
+            root_size,
+            target,
+            area: Area { drawable: x11rb::NONE, offset: (0, 0), origin: (0, 0), border: 0, width: 0, height: 0 },
+            damage: None,
+            region,
+            shm,
+            composite,
+            pixmap: None,
+            hidden: false,
+            active_window_atom,
+            damaged: false,
+            stale: true,
+            bgra: Vec::new(),
+            first_grab: true,
+        };
+        grabber.follow()?;
+        Ok(grabber)
+    }
+
+    // Track the target's current position and size. True when the area moved or changed.
+    fn follow(&mut self) -> Result<bool, String> {
+        self.poll_events()?;
+        // Without a relevant event an idle screen costs no round trip
+        if !std::mem::take(&mut self.stale) {
+            return Ok(false);
+        }
+        let area = self.resolve()?;
+        if area == self.area && self.damage.is_some() {
+            return Ok(false);
+        }
+        if area.drawable != self.area.drawable || self.damage.is_none() {
+            if let Some(old) = self.damage.take() {
+                let _ = self.conn.damage_destroy(old);
+            }
+            let damage = self.conn.generate_id().map_err(|e| e.to_string())?;
+            self.conn
+                .damage_create(damage, area.drawable, damage::ReportLevel::NON_EMPTY)
+                .map_err(|e| e.to_string())?
+                .check()
+                .map_err(|e| format!("Cannot watch screen damage: {}", e))?;
+            self.damage = Some(damage);
+        }
+        if self.composite && area.drawable != self.root {
+            let resized = (area.width, area.height, area.border) != (self.area.width, self.area.height, self.area.border);
+            if area.drawable != self.area.drawable {
+                self.redirect(area.drawable)?;
+            } else if resized || self.pixmap.is_none() {
+                self.name_pixmap(area.drawable)?;
+            }
+        }
+        if (area.width, area.height) != (self.area.width, self.area.height) {
+            self.bgra = vec![0; area.width as usize * area.height as usize * 4];
+        }
+        // Parts that were off-screen may have come into view, read everything again
+        self.first_grab = true;
+        self.area = area;
+        Ok(true)
+    }
+
+    // Have Composite keep `window` off-screen, and forget the window followed until now
+    fn redirect(&mut self, window: Window) -> Result<(), String> {
+        if self.area.drawable != x11rb::NONE && self.area.drawable != self.root {
+            let old = self.area.drawable;
+            let _ = self.conn.composite_unredirect_window(old, composite::Redirect::AUTOMATIC);
+            let _ = self.conn.change_window_attributes(old, &ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT));
+        }
+        // Automatic redirection still shows the window on screen as usual
+        self.conn
+            .composite_redirect_window(window, composite::Redirect::AUTOMATIC)
+            .map_err(|e| e.to_string())?
+            .check()
+            .map_err(|e| format!("Cannot redirect the window: {}", e))?;
+        // Map notifications tell when the window gets new storage
+        self.conn
+            .change_window_attributes(window, &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY))
+            .map_err(|e| e.to_string())?;
+        self.name_pixmap(window)
+    }
+
+    // Name the window's current storage, which Composite replaces whenever the window is
+    // resized or mapped again. An unmapped (minimized...) window has none: the capture is then
+    // hidden until the next MapNotify, and the old pixmap keeps the last picture meanwhile.
+    fn name_pixmap(&mut self, window: Window) -> Result<(), String> {
+        let pixmap = self.conn.generate_id().map_err(|e| e.to_string())?;
+        let named = self.conn
+            .composite_name_window_pixmap(window, pixmap)
+            .map_err(|e| e.to_string())?
+            .check();
+        self.hidden = named.is_err();
+        if self.hidden {
+            return Ok(());
+        }
+        if let Some(old) = self.pixmap.replace(pixmap) {
+            let _ = self.conn.free_pixmap(old);
+        }
+        Ok(())
+    }
+
+    fn resolve(&self) -> Result<Area, String> {
+        match self.target {
+            CaptureTarget::Monitor(index) => {
+                let rect = monitor_rects_on(&self.conn)?
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| format!("Monitor {} is not connected", index + 1))?;
+                Ok(Area {
+                    drawable: self.root,
+                    offset: (rect.x, rect.y),
+                    origin: (rect.x, rect.y),
+                    border: 0,
+                    width: rect.width,
+                    height: rect.height,
+                })
+            }
+            CaptureTarget::Screen => Ok(Area {
+                drawable: self.root,
+                offset: (0, 0),
+                origin: (0, 0),
+                border: 0,
+                width: self.root_size.0,
+                height: self.root_size.1,
+            }),
+            CaptureTarget::Window(window) => self.window_area(window),
+            CaptureTarget::FocusedWindow => match active_window(&self.conn, self.root) {
+                // The caster's own window would show the stream inside itself
+                Ok(window) if is_own_window(&self.conn, window) => match self.damage {
+                    Some(_) => Ok(self.area),
+                    None => self.window_area(self.topmost_other_window()?),
+                },
+                Ok(window) => self.window_area(window),
+                // Nothing focused for a moment (desktop clicked...), stay on the current window
+                Err(_) if self.damage.is_some() => Ok(self.area),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    // Highest window in the stacking order that isn't the caster's
+    fn topmost_other_window(&self) -> Result<Window, String> {
+        stacked_windows(&self.conn, self.root)?
+            .into_iter()
+            .rev()
+            .find(|&window| !is_own_window(&self.conn, window))
+            .ok_or_else(|| "No window to follow".to_string())
+    }
+
+    fn window_area(&self, window: Window) -> Result<Area, String> {
+        let geometry = self.conn
+            .get_geometry(window)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|_| "The window was closed".to_string())?;
+        let position = self.conn
+            .translate_coordinates(window, self.root, 0, 0)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|_| "The window was closed".to_string())?;
+        Ok(Area {
+            drawable: window,
+            offset: (0, 0),
+            origin: (position.dst_x as i32, position.dst_y as i32),
+            border: geometry.border_width as i32,
+            width: (geometry.width as u32).max(1),
+            height: (geometry.height as u32).max(1),
+        })
+    }
+
+    // Part of the area that can be read, in area coordinates (x0, y0, x1, y1): all of a
+    // window's pixmap, only what is on the screen otherwise
+    fn visible(&self) -> (i32, i32, i32, i32) {
+        if self.pixmap.is_some() {
+            return (0, 0, self.area.width as i32, self.area.height as i32);
+        }
+        let (x, y) = self.area.origin;
+        (
+            (-x).max(0),
+            (-y).max(0),
+            (self.root_size.0 as i32 - x).min(self.area.width as i32),
+            (self.root_size.1 as i32 - y).min(self.area.height as i32),
+        )
+    }
+
+    // Read back whatever changed since the last call; the rectangles are relative to the area
+    fn grab(&mut self) -> Result<Vec<DamageRect>, String> {
+        let damaged = std::mem::take(&mut self.damaged) || self.first_grab;
+        let Some(damage) = self.damage else {
+            return Ok(Vec::new());
+        };
+        // Reading a hidden window would only give what covers its place on screen
+        if !damaged || self.hidden {
+            return Ok(Vec::new());
+        }
+
+        // Move the accumulated damage into our region, which also re-arms the notification
+        self.conn
+            .damage_subtract(damage, x11rb::NONE, self.region)
+            .map_err(|e| e.to_string())?;
+        let rectangles = self.conn
+            .xfixes_fetch_region(self.region)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?
+            .rectangles;
+
+        // Damage comes in drawable coordinates and only on-screen pixels can be read
+        let (x0, y0, x1, y1) = self.visible();
+        let within = |x: i32, y: i32, width: i32, height: i32| {
+            clip_area((x.max(x0), y.max(y0), (x + width).min(x1), (y + height).min(y1)), self.area.width, self.area.height)
+        };
+        let mut rects: Vec<DamageRect> = if self.first_grab {
+            within(0, 0, self.area.width as i32, self.area.height as i32).into_iter().collect()
+        } else {
+            rectangles
+                .iter()
+                .filter_map(|r| {
+                    let x = r.x as i32 - self.area.offset.0;
+                    let y = r.y as i32 - self.area.offset.1;
+                    within(x, y, r.width as i32, r.height as i32)
+                })
+                .collect()
+        };
+        if rects.len() > MAX_GRABS_PER_FRAME {
+            let x0 = rects.iter().map(|r| r.x).min().unwrap_or(0);
+            let y0 = rects.iter().map(|r| r.y).min().unwrap_or(0);
+            let x1 = rects.iter().map(|r| r.x + r.width).max().unwrap_or(0);
+            let y1 = rects.iter().map(|r| r.y + r.height).max().unwrap_or(0);
+            rects = vec![DamageRect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 }];
+        }
+
+        for rect in &rects {
+            self.read_area(rect)?;
+        }
+        self.first_grab = false;
+        Ok(rects)
+    }
+
+    // Note what the pending events change: damage to read, or a target to resolve again
+    fn poll_events(&mut self) -> Result<(), String> {
+        let window = self.area.drawable;
+        let is_window = window != self.root && window != x11rb::NONE;
+        while let Some(event) = self.conn.poll_for_event().map_err(|e| format!("X connection lost: {}", e))? {
+            match event {
+                Event::DamageNotify(_) => self.damaged = true,
+                Event::RandrScreenChangeNotify(_) => {
+                    self.resize_screen()?;
+                    self.stale = true;
+                }
+                Event::RandrNotify(_) => self.stale = true,
+                Event::PropertyNotify(event) if event.atom == self.active_window_atom => self.stale = true,
+                Event::ConfigureNotify(event) if is_window && event.window == window => self.stale = true,
+                Event::ReparentNotify(event) if is_window && event.window == window => self.stale = true,
+                Event::DestroyNotify(event) if is_window && event.window == window => self.stale = true,
+                Event::MapNotify(event) if is_window && event.window == window => {
+                    self.name_pixmap(window)?;
+                    self.first_grab = true;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Pick up the new screen size and give the shared memory segment room for it
+    fn resize_screen(&mut self) -> Result<(), String> {
+        let geometry = self.conn
+            .get_geometry(self.root)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?;
+        let size = (geometry.width as u32, geometry.height as u32);
+        if size == self.root_size {
+            return Ok(());
+        }
+        self.root_size = size;
+        if let Some(old) = self.shm.take() {
+            let _ = self.conn.shm_detach(old.seg);
+            self.shm = match attach_shm(&self.conn, size.0 as usize * size.1 as usize * 4) {
+                Ok(segment) => Some(segment),
+                Err(e) => {
+                    eprintln!("XShm unavailable, falling back to GetImage: {}", e);
+                    None
+                }
+            };
+        }
+        // What was off the old screen may be on the new one
+        self.first_grab = true;
+        Ok(())
+    }
+
+    // Copy one on-screen part of the area into the BGRA buffer
+    fn read_area(&mut self, part: &DamageRect) -> Result<(), String> {
+        // A window pixmap starts at the outer corner of the border
+        let (drawable, offset) = match self.pixmap {
+            Some(pixmap) => (pixmap, (self.area.border, self.area.border)),
+            None => (self.area.drawable, self.area.offset),
+        };
+        let x = (offset.0 + part.x as i32) as i16;
+        let y = (offset.1 + part.y as i32) as i16;
+        let (width, height) = (part.width as u16, part.height as u16);
+        let format = u8::from(ImageFormat::Z_PIXMAP);
+        let len = part.width as usize * part.height as usize * 4;
+
+        let (depth, owned);
+        let pixels: &[u8] = match &self.shm {
+            Some(segment) if segment.map.len() >= len => {
+                let reply = self.conn
+                    .shm_get_image(drawable, x, y, width, height, !0, format, segment.seg, 0)
+                    .map_err(|e| e.to_string())?
+                    .reply()
+                    .map_err(|e| format!("Cannot read screen: {}", e))?;
+                depth = reply.depth;
+                &segment.map[..len]
+            }
+            _ => {
+                let reply = self.conn
+                    .get_image(ImageFormat::Z_PIXMAP, drawable, x, y, width, height, !0)
+                    .map_err(|e| e.to_string())?
+                    .reply()
+                    .map_err(|e| format!("Cannot read screen: {}", e))?;
+                depth = reply.depth;
+                owned = reply.data;
+                &owned
+            }
+        };
+        if depth < 24 {
+            return Err(format!("Unsupported screen depth: {}", depth));
+        }
+
+        // 24 and 32 bit ZPixmaps are BGRX with rows packed at 4 bytes per pixel
+        let stride = self.area.width as usize * 4;
+        let start = part.y as usize * stride + part.x as usize * 4;
+        let (width, height) = (part.width as usize, part.height as usize);
+        copy_rows(pixels, width * 4, &mut self.bgra[start..], stride, width * 4, height);
+        Ok(())
+    }
+
+    // Frame of the captured pixels, built on the previous frame's buffer where possible
+    fn frame(&self, recycler: &mut FrameRecycler, changed: Option<&[DamageRect]>) -> Frame {
+        let data = recycler.copy(&self.bgra, self.area.width as usize * 4, changed);
+        Frame::new(data, self.area.width, self.area.height, PixelFormat::Bgra)
+    }
+}
+
+// Share a memory file with the server (MIT-SHM 1.2 file descriptor passing)
+fn attach_shm(conn: &RustConnection, size: usize) -> Result<ShmSegment, String> {
+    let version = conn
+        .shm_query_version()
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?;
+    if (version.major_version, version.minor_version) < (1, 2) {
+        return Err(format!("MIT-SHM {}.{} cannot pass file descriptors", version.major_version, version.minor_version));
+    }
+
+    let dir = if fs::metadata("/dev/shm").is_ok() { "/dev/shm".into() } else { std::env::temp_dir() };
+    let path = dir.join(format!("ustream-{}-{}", std::process::id(), conn.generate_id().map_err(|e| e.to_string())?));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    let _ = fs::remove_file(&path); // Only the descriptors keep it alive
+    file.set_len(size as u64).map_err(|e| e.to_string())?;
+    // SAFETY: the file is private to this process and the X server, which only writes while
+    // we wait for a GetImage reply, and it is never truncated after this point
+    let map = unsafe { MmapMut::map_mut(&file) }.map_err(|e| format!("Cannot map shared memory: {}", e))?;
+
+    let seg = conn.generate_id().map_err(|e| e.to_string())?;
+    conn.shm_attach_fd(seg, OwnedFd::from(file), false)
+        .map_err(|e| e.to_string())?
+        .check()
+        .map_err(|e| format!("Server refused shared memory: {}", e))?;
+    Ok(ShmSegment { seg, map })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use crate::virtual_display::VirtualDisplay;
+    use x11rb::protocol::xproto::{ChangeWindowAttributesAux, CreateWindowAux, WindowClass};
+
+    // Next frame within a few seconds
+    fn next_frame(capture: &mut X11Capture) -> Option<Frame> {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            if let Some(frame) = capture.receive_frame() {
+                return Some(frame);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        None
+    }
+
+    fn rgb_at(frame: &Frame, x: usize, y: usize) -> [u8; 3] {
+        let index = y * frame.stride + x * 4;
+        let bgra = &frame.data[index..index + 4];
+        [bgra[2], bgra[1], bgra[0]]
+    }
+
+    // Repaint a window with a solid colour, as an application would
+    fn fill(conn: &RustConnection, window: Window, pixel: u32) {
+        conn.change_window_attributes(window, &ChangeWindowAttributesAux::new().background_pixel(pixel)).unwrap();
+        conn.clear_area(false, window, 0, 0, 0, 0).unwrap();
+        conn.flush().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn reports_damage_only_after_a_change() {
+        let display = VirtualDisplay::launch("sleep 30", 320, 240).unwrap();
+        let (conn, screen) = x11rb::connect(Some(display.name())).unwrap();
+        let root = conn.setup().roots[screen].root;
+        let mut capture = X11Capture::on_display(Some(display.name().to_string()), CaptureTarget::Screen, 30).unwrap();
+
+        let first = next_frame(&mut capture).expect("no first frame");
+        assert_eq!((first.width, first.height), (320, 240));
+        thread::sleep(Duration::from_millis(300));
+        capture.receive_frame();
+        thread::sleep(Duration::from_millis(500));
+        assert!(capture.receive_frame().is_none(), "nothing changed on the screen");
+
+        fill(&conn, root, 0xff0000);
+        let frame = next_frame(&mut capture).expect("no frame after the change");
+        assert!(!capture.take_damage().unwrap_or_default().is_empty());
+        assert_eq!(rgb_at(&frame, 10, 10), [255, 0, 0]);
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn captures_a_window_and_survives_unmapping_it() {
+        let display = VirtualDisplay::launch("sleep 30", 320, 240).unwrap();
+        let (conn, screen) = x11rb::connect(Some(display.name())).unwrap();
+        let root = conn.setup().roots[screen].root;
+        let window = conn.generate_id().unwrap();
+        conn.create_window(0, window, root, 10, 10, 40, 30, 0, WindowClass::INPUT_OUTPUT, 0, &CreateWindowAux::new().background_pixel(0x0000ff))
+            .unwrap();
+        conn.map_window(window).unwrap();
+        conn.flush().unwrap();
+
+        let mut capture = X11Capture::on_display(Some(display.name().to_string()), CaptureTarget::Window(window), 30).unwrap();
+        let frame = next_frame(&mut capture).expect("no window frame");
+        assert_eq!((frame.width, frame.height), (40, 30));
+        assert_eq!(rgb_at(&frame, 20, 15), [0, 0, 255]);
+
+        // Hiding the window pauses the capture instead of failing it
+        conn.unmap_window(window).unwrap();
+        conn.flush().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let state = capture.state().unwrap();
+        assert!(*state.borrow() == CaptureState::Running);
+
+        conn.map_window(window).unwrap();
+        fill(&conn, window, 0x00ff00);
+        let started = Instant::now();
+        loop {
+            if let Some(frame) = capture.receive_frame() {
+                if rgb_at(&frame, 20, 15) == [0, 255, 0] {
+                    break;
+                }
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "window was not captured again");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}